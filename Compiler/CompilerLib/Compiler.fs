@@ -35,17 +35,31 @@ let getOrAddLocal (env: CompilerEnv) (name: string) : int * CompilerEnv =
 type Emitted =
     | Instruction of Opcode * string option
     | FunctionLabel of Label
+    // `.fn label arity locals`
+    | FunctionHeader of Label * int * int
     | EmittedLabel of Label
     | Comment of string
 
 let emit opcode : Emitted = Instruction(opcode, None)
 let emitWithComment (opcode, comment) = Instruction(opcode, comment)
 
+// Escape strings the same way the VM's assembler reads them back
+let escapeString (s: string) =
+    s
+    |> Seq.map (function
+        | '\\' -> "\\\\"
+        | '"' -> "\\\""
+        | '\n' -> "\\n"
+        | '\r' -> "\\r"
+        | '\t' -> "\\t"
+        | c -> string c)
+    |> String.concat ""
+
 let formatLiteral literal =
     match literal with
     | Int i -> i.ToString()
     | Float f -> f.ToString()
-    | LString s -> $"\"{s}\""
+    | LString s -> $"\"{escapeString s}\""
     | Boolean b -> b.ToString()
     | Identifier s -> Ident.value s
 
@@ -155,12 +169,10 @@ let rec compileExpression e (env: CompilerEnv) : Emitted list * CompilerEnv =
         instrs, env''
     | Expression.Call fn ->
         // Compile function call
-        // push all expression arguments onto stack in reverse order
-        // we push in reverse order so that they can be popped from stack
-        // and stored as function locals in the correct order
+        // push all expression arguments onto stack in order,
+        // CALL moves them into the function's locals with the first argument in local 0
         let body, env2 =
             fn.arguments
-            |> List.rev
             |> List.fold
                 (fun (acc, currentEnv) stmt ->
                     let emitted, newEnv = compileExpression stmt currentEnv
@@ -176,9 +188,6 @@ let rec compileDeclaration (declaration: Declaration) env : Emitted list * Compi
     | Statement statement -> compileStatement statement env
 
 and compileFunction (fn: Function) env : Emitted list * CompilerEnv =
-    // Add function label
-    let label = FunctionLabel(fnLabel fn.name)
-
     let paramNames =
         match fn.parameters with
         | Some ps -> List.map Ident.value ps
@@ -191,13 +200,6 @@ and compileFunction (fn: Function) env : Emitted list * CompilerEnv =
             locals = paramLocals
             nextSlot = List.length paramNames }
 
-    // pop all arguments off the stack and store them into their respective locals
-    let storeParams: Emitted list =
-        paramLocals //
-        |> Map.toList
-        |> List.sortBy snd // store in argument order which was pushed onto the stack
-        |> List.map (fun (k, v) -> emitWithComment (StoreLocal v, Some $"push {k}"))
-
     // fn declarations should not be nested
     let decl_fns =
         fn.body
@@ -230,19 +232,25 @@ and compileFunction (fn: Function) env : Emitted list * CompilerEnv =
             ([], env2)
 
 
-    // If we are in the main function, we don't want to return, but rather halt
-    let returnOp =
+    // If we are in the main function, we don't want to return, but rather halt.
+    // Other functions are declared with `.fn`, so they get their own part of the stack
+    // and RET hands back the last value the body left (or nil) and drops the rest
+    let label, returnOp =
         match Ident.value fn.name with
-        | "main" -> [ emit Halt ]
-        | _ -> [ emit Return ]
+        | "main" -> FunctionLabel(fnLabel fn.name), [ emit Halt ]
+        | _ -> FunctionHeader(fnLabel fn.name, List.length paramNames, env2.nextSlot), [ emit Return ]
 
-    [ label ] @ storeParams @ body @ returnOp @ nested_functions, env3
+    [ label ] @ body @ returnOp @ nested_functions, env3
 
 and compileStatement (statement: Statement) env : Emitted list * CompilerEnv =
     match statement with
     | Statement.Print e ->
         let exprInstrs, env' = (compileExpression e env)
         exprInstrs @ [ emit Print ], env'
+    | Statement.ExprStatement(Expression.Call _ as e) ->
+        // a call on its own discards the value the function returns
+        let exprInstrs, env' = (compileExpression e env)
+        exprInstrs @ [ emit Pop ], env'
     | Statement.ExprStatement e -> (compileExpression e env)
     | If(condition, ifBody, elseBody) ->
         // if the expression is false, jump to end
@@ -317,6 +325,7 @@ and allStatementLiterals (statement: Statement) : Literal list =
 let emittedToString emitted =
     match emitted with
     | FunctionLabel s -> "\n." + Label.value s
+    | FunctionHeader(s, arity, locals) -> $"\n.fn {Label.value s} {arity} {locals}"
     | EmittedLabel s -> "." + Label.value s
     | Comment s -> "; " + s
     | Instruction(opcode, stringOption) ->
@@ -2,9 +2,16 @@ mod utils;
 
 use std::{cell::RefCell, rc::Rc};
 
-use vm::{assembler::parse_assembly, vm::VM};
+use vm::{
+    assembler::try_parse_assembly,
+    instruction::Program,
+    vm::{RunOutcome, VM},
+};
 use wasm_bindgen::prelude::*;
 
+/// How many instructions a program may run before it's assumed to be stuck
+const FUEL: u64 = 100_000_000;
+
 // #[wasm_bindgen]
 // extern "C" {
 //     fn alert(s: &str);
@@ -13,8 +20,23 @@ use wasm_bindgen::prelude::*;
 #[wasm_bindgen]
 pub fn run_asm(asm_text: &str) -> Vec<String> {
     utils::set_panic_hook();
-    let program = parse_assembly(&asm_text);
+    match try_parse_assembly(asm_text) {
+        Ok(program) => run_program(program),
+        Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+    }
+}
 
+/// Runs a program precompiled to the `.lbc` bytecode format
+#[wasm_bindgen]
+pub fn run_bytecode(bytes: &[u8]) -> Vec<String> {
+    utils::set_panic_hook();
+    match Program::from_bytes(bytes) {
+        Ok(program) => run_program(program),
+        Err(err) => vec![err.to_string()],
+    }
+}
+
+fn run_program(program: Program) -> Vec<String> {
     let output = Rc::new(RefCell::new(Vec::new()));
     let output_clone = Rc::clone(&output);
 
@@ -22,7 +44,16 @@ pub fn run_asm(asm_text: &str) -> Vec<String> {
     vm.set_output_handler(move |val| {
         output_clone.borrow_mut().push(format!("{}", val));
     });
-    vm.run();
+    let result = vm.run_with_fuel(FUEL);
     vm.clear_output_handler();
-    Rc::try_unwrap(output).unwrap().into_inner()
+
+    let mut output = Rc::try_unwrap(output).unwrap().into_inner();
+    match result {
+        Ok(RunOutcome::Finished) => {}
+        Ok(RunOutcome::OutOfFuel | RunOutcome::Paused) => output.push(format!(
+            "stopped after {FUEL} instructions, is there an infinite loop?"
+        )),
+        Err(err) => output.push(err.to_string()),
+    }
+    output
 }
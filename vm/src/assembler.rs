@@ -1,190 +1,940 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    num::IntErrorKind,
+    str::FromStr,
+};
 
-use crate::instruction::{ConstantIndex, ConstantValue, Opcode, Program};
+use crate::instruction::{
+    ConstantIndex, ConstantValue, DebugInfo, Function, MAX_LOCALS, Opcode, Program,
+};
 
+/// Assembles the program, panicking with every diagnostic if the source is invalid.
+/// Use `try_parse_assembly` when embedding the assembler somewhere a panic is unacceptable.
 pub fn parse_assembly(asm: &str) -> Program {
-    let (code, entry) = parse_opcodes_with_labels(asm);
-    Program {
-        code,
-        entry,
-        constants: parse_constants(asm),
+    try_parse_assembly(asm).unwrap_or_else(|errors| {
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        panic!("Failed to assemble program:\n{}", messages.join("\n"))
+    })
+}
+
+/// Assembles the program, collecting every problem in the source instead of stopping at the first.
+pub fn try_parse_assembly(asm: &str) -> Result<Program, Vec<AssembleError>> {
+    let mut errors = Vec::new();
+    let lines = source_lines(asm, &mut errors);
+    let constants = parse_constants(&lines, &mut errors);
+    let mut program = parse_opcodes_with_labels(&lines, &constants, &mut errors);
+
+    if !errors.is_empty() {
+        errors.sort_by_key(|e| (e.line, e.column));
+        errors.dedup();
+        return Err(errors);
+    }
+
+    program.constants = constant_pool(constants);
+    Ok(program)
+}
+
+/// A single problem found while assembling, pointing at the offending source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,     // 1-based line number
+    pub column: usize,   // 1-based column (in chars) of the offending token
+    pub snippet: String, // the full source line
+    pub kind: AssembleErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssembleErrorKind {
+    UnknownInstruction(String),
+    MissingOperand {
+        instruction: String,
+    },
+    InvalidOperand {
+        instruction: String,
+        operand: String,
+    },
+    UnexpectedOperand {
+        instruction: String,
+        operand: String,
+    },
+    UnknownLabel(String),
+    DuplicateLabel(String),
+    MalformedConst,
+    InvalidConstIndex(String),
+    DuplicateConstIndex(u32),
+    ConstIndexOutOfRange(u32),
+    UndefinedConstant(u32),
+    InvalidConstValue(String),
+    IntOutOfRange(String),
+    UnterminatedString,
+    InvalidEscape(String),
+    TooFewLocals {
+        function: String,
+        arity: usize,
+        locals: usize,
+    },
+    TooManyLocals {
+        function: String,
+        locals: usize,
+    },
+    LocalOutOfRange(usize),
+    ArityMismatch {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    NotAFunction(String),
+}
+
+impl Display for AssembleErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssembleErrorKind::UnknownInstruction(x) => write!(f, "unknown instruction `{x}`"),
+            AssembleErrorKind::MissingOperand { instruction } => {
+                write!(f, "`{instruction}` needs an argument")
+            }
+            AssembleErrorKind::InvalidOperand {
+                instruction,
+                operand,
+            } => write!(f, "invalid argument `{operand}` for `{instruction}`"),
+            AssembleErrorKind::UnexpectedOperand {
+                instruction,
+                operand,
+            } => write!(f, "unexpected argument `{operand}` for `{instruction}`"),
+            AssembleErrorKind::UnknownLabel(x) => write!(f, "unknown label `{x}`"),
+            AssembleErrorKind::DuplicateLabel(x) => write!(f, "label `{x}` is defined twice"),
+            AssembleErrorKind::MalformedConst => {
+                write!(f, "malformed constant, expected `.const <index> <value>`")
+            }
+            AssembleErrorKind::InvalidConstIndex(x) => {
+                write!(f, "invalid constant index `{x}`")
+            }
+            AssembleErrorKind::DuplicateConstIndex(x) => {
+                write!(f, "constant {x} is declared twice")
+            }
+            AssembleErrorKind::ConstIndexOutOfRange(x) => write!(
+                f,
+                "constant index {x} is out of range, indices go up to {}",
+                MAX_CONSTANTS - 1
+            ),
+            AssembleErrorKind::UndefinedConstant(x) => write!(f, "constant {x} is never declared"),
+            AssembleErrorKind::InvalidConstValue(x) => write!(f, "unable to parse constant `{x}`"),
+            AssembleErrorKind::IntOutOfRange(x) => {
+                write!(f, "integer `{x}` doesn't fit in 64 bits")
+            }
+            AssembleErrorKind::UnterminatedString => write!(f, "unterminated string literal"),
+            AssembleErrorKind::InvalidEscape(x) => write!(f, "invalid escape sequence `{x}`"),
+            AssembleErrorKind::TooFewLocals {
+                function,
+                arity,
+                locals,
+            } => write!(
+                f,
+                "function `{function}` takes {arity} arguments but only has {locals} locals to hold them"
+            ),
+            AssembleErrorKind::TooManyLocals { function, locals } => write!(
+                f,
+                "function `{function}` has {locals} locals, but a frame holds at most {MAX_LOCALS}"
+            ),
+            AssembleErrorKind::LocalOutOfRange(x) => write!(
+                f,
+                "local {x} is out of range, a frame holds at most {MAX_LOCALS} locals"
+            ),
+            AssembleErrorKind::ArityMismatch {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "function `{function}` takes {expected} arguments but is called with {found}"
+            ),
+            AssembleErrorKind::NotAFunction(x) => {
+                write!(
+                    f,
+                    "`{x}` is not declared with `.fn`, so its arguments can't be checked"
+                )
+            }
+        }
+    }
+}
+
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gutter = self.line.to_string().len();
+        writeln!(f, "error: {}", self.kind)?;
+        writeln!(
+            f,
+            "{:gutter$}--> line {}, column {}",
+            "", self.line, self.column
+        )?;
+        writeln!(f, "{} | {}", self.line, self.snippet)?;
+        write!(f, "{:gutter$} | {:>width$}", "", "^", width = self.column)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// A token in the source, used to point diagnostics at the offending text
+#[derive(Debug, Clone, Copy)]
+struct SourceLoc<'a> {
+    line: usize,
+    text: &'a str,  // the full source line
+    token: &'a str, // must be a subslice of `text`
+}
+
+impl<'a> SourceLoc<'a> {
+    fn new(line: usize, text: &'a str, token: &'a str) -> SourceLoc<'a> {
+        SourceLoc { line, text, token }
+    }
+
+    /// Another token on the same line
+    fn at(&self, token: &'a str) -> SourceLoc<'a> {
+        SourceLoc::new(self.line, self.text, token)
+    }
+
+    fn error(&self, kind: AssembleErrorKind) -> AssembleError {
+        let offset = self.token.as_ptr() as usize - self.text.as_ptr() as usize;
+        AssembleError {
+            line: self.line,
+            column: self.text[..offset].chars().count() + 1,
+            snippet: self.text.to_string(),
+            kind,
+        }
     }
 }
 
 /// Represents an unresolved jump before label resolution
 #[derive(Debug)]
-enum UnresolvedOpcode {
+enum UnresolvedOpcode<'a> {
     Resolved(Opcode),
-    CallLabel(String),
-    JumpLabel(String),
-    JumpZeroLabel(String),
-    JumpNotZeroLabel(String),
+    /// The target label and the optional argument count
+    CallLabel(SourceLoc<'a>, Option<SourceLoc<'a>>),
+    TailCallLabel(SourceLoc<'a>, Option<SourceLoc<'a>>),
+    JumpLabel(SourceLoc<'a>),
+    JumpZeroLabel(SourceLoc<'a>),
+    JumpNotZeroLabel(SourceLoc<'a>),
+}
+
+/// A logical line of source.
+/// String literals may contain newlines, so a single line can span several physical lines.
+struct Line<'a> {
+    number: usize, // 1-based number of the physical line it starts on
+    text: &'a str, // the whole line, including any comment
+    code: &'a str, // the line with its comment removed
+}
+
+#[derive(Clone, Copy)]
+enum LexState {
+    Code,
+    Comment,
+    Str,
+    RawStr(usize), // number of `#`s delimiting the raw string
+}
+
+/// Splits the source into lines, stripping `;` comments that aren't inside a string literal
+fn source_lines<'a>(asm: &'a str, errors: &mut Vec<AssembleError>) -> Vec<Line<'a>> {
+    let mut lines = Vec::new();
+    let mut state = LexState::Code;
+    let mut physical_line = 1;
+    let mut line_number = 1;
+    let mut start = 0;
+    let mut code_end = None;
+    let mut string_start = 0;
+
+    let mut chars = asm.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (state, c) {
+            (LexState::Code | LexState::Comment, '\n') => {
+                let text = asm[start..i].trim_end_matches('\r');
+                let code = &asm[start..code_end.unwrap_or(i)];
+                lines.push(Line {
+                    number: line_number,
+                    text,
+                    code: code.trim_end_matches('\r'),
+                });
+                physical_line += 1;
+                line_number = physical_line;
+                start = i + 1;
+                code_end = None;
+                state = LexState::Code;
+            }
+            (LexState::Code, ';') => {
+                code_end = Some(i);
+                state = LexState::Comment;
+            }
+            (LexState::Code, '"') => {
+                string_start = i;
+                state = LexState::Str;
+            }
+            (LexState::Code, 'r') => {
+                // `r"..."` or `r#"..."#` starts a raw string, but only at the start of a token
+                let at_token_start = asm[..i]
+                    .chars()
+                    .next_back()
+                    .is_none_or(|x| x.is_whitespace());
+                let hashes = asm[i + 1..].chars().take_while(|x| *x == '#').count();
+                if at_token_start && asm[i + 1 + hashes..].starts_with('"') {
+                    string_start = i;
+                    state = LexState::RawStr(hashes);
+                    chars.nth(hashes); // skip the hashes and opening quote
+                }
+            }
+            (LexState::Str, '\\') => {
+                if let Some((_, '\n')) = chars.next() {
+                    physical_line += 1;
+                }
+            }
+            (LexState::Str, '"') => state = LexState::Code,
+            (LexState::RawStr(hashes), '"') if asm[i + 1..].starts_with(&"#".repeat(hashes)) => {
+                if hashes > 0 {
+                    chars.nth(hashes - 1);
+                }
+                state = LexState::Code;
+            }
+            (LexState::Str | LexState::RawStr(_), '\n') => physical_line += 1,
+            _ => {}
+        }
+    }
+
+    let text = &asm[start..];
+    if matches!(state, LexState::Str | LexState::RawStr(_)) {
+        errors.push(
+            SourceLoc::new(line_number, text, &asm[string_start..])
+                .error(AssembleErrorKind::UnterminatedString),
+        );
+    }
+    if !text.is_empty() {
+        lines.push(Line {
+            number: line_number,
+            text,
+            code: &asm[start..code_end.unwrap_or(asm.len())],
+        });
+    }
+
+    lines
+}
+
+/// Pulls the next operand token off the instruction, recording an error if there is none
+fn next_operand<'a>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    instr: SourceLoc<'a>,
+    errors: &mut Vec<AssembleError>,
+) -> Option<SourceLoc<'a>> {
+    match parts.next() {
+        Some(token) => Some(instr.at(token)),
+        None => {
+            errors.push(instr.error(AssembleErrorKind::MissingOperand {
+                instruction: instr.token.to_string(),
+            }));
+            None
+        }
+    }
+}
+
+/// Pulls the next operand off the instruction and parses it, recording an error on failure
+fn operand<'a, T: FromStr>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    instr: SourceLoc<'a>,
+    errors: &mut Vec<AssembleError>,
+) -> Option<T> {
+    let arg = next_operand(parts, instr, errors)?;
+    match arg.token.parse::<T>() {
+        Ok(x) => Some(x),
+        Err(_) => {
+            errors.push(arg.error(AssembleErrorKind::InvalidOperand {
+                instruction: instr.token.to_string(),
+                operand: arg.token.to_string(),
+            }));
+            None
+        }
+    }
+}
+
+/// Pulls a local slot operand off the instruction, checking that a frame can hold it
+fn local_operand<'a>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    instr: SourceLoc<'a>,
+    errors: &mut Vec<AssembleError>,
+) -> Option<usize> {
+    let arg = next_operand(parts, instr, errors)?;
+    let Ok(index) = arg.token.parse::<usize>() else {
+        errors.push(arg.error(AssembleErrorKind::InvalidOperand {
+            instruction: instr.token.to_string(),
+            operand: arg.token.to_string(),
+        }));
+        return None;
+    };
+
+    if index >= MAX_LOCALS {
+        errors.push(arg.error(AssembleErrorKind::LocalOutOfRange(index)));
+        return None;
+    }
+
+    Some(index)
+}
+
+/// Pulls the constant index operand off `PUSH_CONST`, checking that it was declared
+fn constant_operand<'a>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    instr: SourceLoc<'a>,
+    constants: &Constants,
+    errors: &mut Vec<AssembleError>,
+) -> Option<ConstantIndex> {
+    let arg = next_operand(parts, instr, errors)?;
+    let Ok(index) = arg.token.parse::<u32>() else {
+        errors.push(arg.error(AssembleErrorKind::InvalidOperand {
+            instruction: instr.token.to_string(),
+            operand: arg.token.to_string(),
+        }));
+        return None;
+    };
+
+    if !constants.contains_key(&index) {
+        errors.push(arg.error(AssembleErrorKind::UndefinedConstant(index)));
+        return None;
+    }
+
+    Some(ConstantIndex(index))
+}
+
+fn is_fn_directive(line: &str) -> bool {
+    line.split_whitespace().next() == Some(".fn")
+}
+
+/// Parses `.fn name arity locals`, declaring a function that starts at `pc`.
+/// `locals` counts every local slot, including the ones arguments arrive in.
+fn parse_fn_directive<'a>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    directive: SourceLoc<'a>,
+    pc: usize,
+    errors: &mut Vec<AssembleError>,
+) -> Option<(SourceLoc<'a>, Function)> {
+    let name = next_operand(parts, directive, errors)?;
+    let arity = operand(parts, directive, errors)?;
+    let locals = operand(parts, directive, errors)?;
+    if let Some(extra) = parts.next() {
+        errors.push(
+            directive
+                .at(extra)
+                .error(AssembleErrorKind::UnexpectedOperand {
+                    instruction: directive.token.to_string(),
+                    operand: extra.to_string(),
+                }),
+        );
+    }
+
+    if locals > MAX_LOCALS {
+        errors.push(name.error(AssembleErrorKind::TooManyLocals {
+            function: name.token.to_string(),
+            locals,
+        }));
+        return None;
+    }
+    if locals < arity {
+        errors.push(name.error(AssembleErrorKind::TooFewLocals {
+            function: name.token.to_string(),
+            arity,
+            locals,
+        }));
+        return None;
+    }
+
+    let function = Function {
+        name: name.token.to_string(),
+        pc,
+        arity,
+        locals,
+    };
+    Some((name, function))
+}
+
+/// Checks the argument count given in `CALL label count` against the function's declaration.
+/// The count is optional, since the declaration already says how many values a call moves,
+/// and calls to a plain label can't give one.
+fn check_arity(
+    instruction: &str,
+    label: SourceLoc,
+    argc: Option<SourceLoc>,
+    functions: &[Function],
+    errors: &mut Vec<AssembleError>,
+) {
+    let Some(argc) = argc else {
+        return;
+    };
+    let Ok(found) = argc.token.parse::<usize>() else {
+        errors.push(argc.error(AssembleErrorKind::InvalidOperand {
+            instruction: instruction.to_string(),
+            operand: argc.token.to_string(),
+        }));
+        return;
+    };
+
+    match functions.iter().find(|x| x.name == label.token) {
+        Some(function) if function.arity != found => {
+            errors.push(argc.error(AssembleErrorKind::ArityMismatch {
+                function: function.name.clone(),
+                expected: function.arity,
+                found,
+            }))
+        }
+        Some(_) => {}
+        None => errors.push(label.error(AssembleErrorKind::NotAFunction(label.token.to_string()))),
+    }
 }
 
 /// Parses the input and resolves jumps
-fn parse_opcodes_with_labels(asm: &str) -> (Vec<Opcode>, usize) {
+fn parse_opcodes_with_labels<'a>(
+    lines: &[Line<'a>],
+    constants: &Constants,
+    errors: &mut Vec<AssembleError>,
+) -> Program {
     let mut opcodes = Vec::new();
+    let mut natives: Vec<String> = Vec::new();
+    let mut functions: Vec<Function> = Vec::new();
     let mut labels = HashMap::new();
     let mut unresolved = Vec::new();
     let mut instruction_index = 0;
+    let mut debug = DebugInfo::default();
 
-    for line in asm.lines().map(str::trim).filter(|l| !l.is_empty()) {
-        if line.starts_with(".const") {
+    for &Line { number, text, code } in lines {
+        let line = code.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if is_const_directive(line) {
             continue; // Skip constants here
         }
 
+        if is_fn_directive(line) {
+            let mut parts = line.split_whitespace();
+            let loc = SourceLoc::new(number, text, parts.next().unwrap());
+            let Some((name, function)) =
+                parse_fn_directive(&mut parts, loc, instruction_index, errors)
+            else {
+                continue;
+            };
+            // a function's name is also a label for its first instruction
+            if labels.insert(name.token, instruction_index).is_some() {
+                errors.push(name.error(AssembleErrorKind::DuplicateLabel(function.name.clone())));
+            } else {
+                debug
+                    .labels
+                    .push((function.name.clone(), instruction_index));
+                functions.push(function);
+            }
+            continue;
+        }
+
         if line.ends_with(':') {
             continue; // Skip labels like `main:`
         }
 
-        if line.starts_with('.') {
+        if let Some(label) = line.strip_prefix('.') {
             // Record the label as the *current* instruction index
-            let label = line.trim_start_matches('.').to_string();
-            //println!("{:?} {:?}", line, instruction_index);
-            labels.insert(label, instruction_index);
+            if labels.insert(label, instruction_index).is_some() {
+                errors.push(
+                    SourceLoc::new(number, text, line)
+                        .error(AssembleErrorKind::DuplicateLabel(label.to_string())),
+                );
+            } else {
+                debug.labels.push((label.to_string(), instruction_index));
+            }
             continue;
         }
 
-        let code = line.split(';').next().unwrap().trim(); // Remove comment
-        if code.is_empty() {
-            continue;
-        }
+        let mut parts = line.split_whitespace();
+        let loc = SourceLoc::new(number, text, parts.next().unwrap());
 
-        let mut parts = code.split_whitespace();
-        let instr = parts.next().unwrap();
-
-        match instr {
-            "PUSH_CONST" => {
-                let index_str = parts.next().expect("PUSH_CONST needs an argument");
-                let index: ConstantIndex =
-                    ConstantIndex(index_str.parse::<u32>().expect("Invalid constant index"));
-                unresolved.push(UnresolvedOpcode::Resolved(Opcode::Push(index)));
-            }
-            "POP" => {
-                unresolved.push(UnresolvedOpcode::Resolved(Opcode::Pop));
-            }
-            "STORE_LOCAL" => {
-                let index_str = parts.next().expect("STORE_LOCAL needs an argument");
-                let index: usize = index_str.parse::<usize>().expect("Invalid constant index");
-                unresolved.push(UnresolvedOpcode::Resolved(Opcode::StoreLocal(index)));
-            }
-            "LOAD_LOCAL" => {
-                let index_str = parts.next().expect("LOAD_LOCAL needs an argument");
-                let index: usize = index_str.parse::<usize>().expect("Invalid constant index");
-                unresolved.push(UnresolvedOpcode::Resolved(Opcode::LoadLocal(index)));
-            }
-            "ADD" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::Add)),
-            "SUB" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::Subtract)),
-            "MUL" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::Multiply)),
-            "DIV" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::Divide)),
-            "MOD" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::Modulo)),
-            "PRINT" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::Print)),
-            "EQ" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::Equals)),
-            "NEQ" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::NotEqual)),
-            "INC" => {
-                let index_str = parts.next().expect("INC needs an argument");
-                let index: usize = index_str.parse::<usize>().expect("Invalid constant index");
-                unresolved.push(UnresolvedOpcode::Resolved(Opcode::Increment(index)));
-            }
-            "GT" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::GreaterThan)),
-            "GTE" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::GreaterThanEqual)),
-            "LT" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::LessThan)),
-            "LTE" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::LessThanEqual)),
-            "HALT" => unresolved.push(UnresolvedOpcode::Resolved(Opcode::Halt)),
-            "JUMP" => {
-                let label = parts.next().expect("JUMP needs a label").to_string();
-                unresolved.push(UnresolvedOpcode::JumpLabel(label));
-            }
-            "CALL" => {
-                let label = parts.next().expect("CALL needs a label").to_string();
-                unresolved.push(UnresolvedOpcode::CallLabel(label));
-            }
-            "RET" => {
-                unresolved.push(UnresolvedOpcode::Resolved(Opcode::Return));
-            }
-            "JUMPZ" => {
-                let label = parts.next().expect("JUMPZ needs a label").to_string();
-                unresolved.push(UnresolvedOpcode::JumpZeroLabel(label));
-            }
+        let opcode = match loc.token {
+            "PUSH_CONST" => constant_operand(&mut parts, loc, constants, errors)
+                .map(|x| UnresolvedOpcode::Resolved(Opcode::Push(x))),
+            "POP" => Some(UnresolvedOpcode::Resolved(Opcode::Pop)),
+            "STORE_LOCAL" => local_operand(&mut parts, loc, errors)
+                .map(|x| UnresolvedOpcode::Resolved(Opcode::StoreLocal(x))),
+            "LOAD_LOCAL" => local_operand(&mut parts, loc, errors)
+                .map(|x| UnresolvedOpcode::Resolved(Opcode::LoadLocal(x))),
+            "ADD" => Some(UnresolvedOpcode::Resolved(Opcode::Add)),
+            "SUB" => Some(UnresolvedOpcode::Resolved(Opcode::Subtract)),
+            "MUL" => Some(UnresolvedOpcode::Resolved(Opcode::Multiply)),
+            "DIV" => Some(UnresolvedOpcode::Resolved(Opcode::Divide)),
+            "MOD" => Some(UnresolvedOpcode::Resolved(Opcode::Modulo)),
+            "TO_INT" => Some(UnresolvedOpcode::Resolved(Opcode::ToInt)),
+            "TO_FLOAT" => Some(UnresolvedOpcode::Resolved(Opcode::ToFloat)),
+            "TO_STR" => Some(UnresolvedOpcode::Resolved(Opcode::ToStr)),
+            "LEN" => Some(UnresolvedOpcode::Resolved(Opcode::Length)),
+            "SUBSTR" => Some(UnresolvedOpcode::Resolved(Opcode::Substring)),
+            "INDEX_OF" => Some(UnresolvedOpcode::Resolved(Opcode::IndexOf)),
+            "UPPER" => Some(UnresolvedOpcode::Resolved(Opcode::Upper)),
+            "LOWER" => Some(UnresolvedOpcode::Resolved(Opcode::Lower)),
+            "TRIM" => Some(UnresolvedOpcode::Resolved(Opcode::Trim)),
+            "SPLIT" => Some(UnresolvedOpcode::Resolved(Opcode::Split)),
+            "PRINT" => Some(UnresolvedOpcode::Resolved(Opcode::Print)),
+            "EQ" => Some(UnresolvedOpcode::Resolved(Opcode::Equals)),
+            "NEQ" => Some(UnresolvedOpcode::Resolved(Opcode::NotEqual)),
+            "INC" => local_operand(&mut parts, loc, errors)
+                .map(|x| UnresolvedOpcode::Resolved(Opcode::Increment(x))),
+            "GT" => Some(UnresolvedOpcode::Resolved(Opcode::GreaterThan)),
+            "GTE" => Some(UnresolvedOpcode::Resolved(Opcode::GreaterThanEqual)),
+            "LT" => Some(UnresolvedOpcode::Resolved(Opcode::LessThan)),
+            "LTE" => Some(UnresolvedOpcode::Resolved(Opcode::LessThanEqual)),
+            "HALT" => Some(UnresolvedOpcode::Resolved(Opcode::Halt)),
+            "JUMP" => next_operand(&mut parts, loc, errors).map(UnresolvedOpcode::JumpLabel),
+            "CALL" => next_operand(&mut parts, loc, errors)
+                .map(|label| UnresolvedOpcode::CallLabel(label, parts.next().map(|x| loc.at(x)))),
+            "TAIL_CALL" => next_operand(&mut parts, loc, errors).map(|label| {
+                UnresolvedOpcode::TailCallLabel(label, parts.next().map(|x| loc.at(x)))
+            }),
+            "CALL_NATIVE" => next_operand(&mut parts, loc, errors).map(|name| {
+                let idx = match natives.iter().position(|x| x == name.token) {
+                    Some(idx) => idx,
+                    None => {
+                        natives.push(name.token.to_string());
+                        natives.len() - 1
+                    }
+                };
+                UnresolvedOpcode::Resolved(Opcode::CallNative(idx))
+            }),
+            "RET" => Some(UnresolvedOpcode::Resolved(Opcode::Return)),
+            "RET_VOID" => Some(UnresolvedOpcode::Resolved(Opcode::ReturnVoid)),
+            "JUMPZ" => next_operand(&mut parts, loc, errors).map(UnresolvedOpcode::JumpZeroLabel),
             "JUMPNZ" => {
-                let label = parts.next().expect("JUMPZ needs a label").to_string();
-                unresolved.push(UnresolvedOpcode::JumpNotZeroLabel(label));
+                next_operand(&mut parts, loc, errors).map(UnresolvedOpcode::JumpNotZeroLabel)
             }
-            _ => panic!("Unknown instruction: {}", instr),
+            _ => {
+                errors
+                    .push(loc.error(AssembleErrorKind::UnknownInstruction(loc.token.to_string())));
+                None
+            }
+        };
+
+        if let Some(opcode) = opcode {
+            if let Some(extra) = parts.next() {
+                errors.push(loc.at(extra).error(AssembleErrorKind::UnexpectedOperand {
+                    instruction: loc.token.to_string(),
+                    operand: extra.to_string(),
+                }));
+            }
+            unresolved.push(opcode);
         }
 
+        debug.lines.push(number);
         instruction_index += 1; // Count only real instructions
     }
 
-    //println!("{:?}", labels);
-
     // Second pass: resolve JP labels
     for entry in unresolved {
-        match entry {
-            UnresolvedOpcode::Resolved(op) => opcodes.push(op),
-            UnresolvedOpcode::JumpLabel(label) => {
-                let addr = *labels
-                    .get(&label)
-                    .unwrap_or_else(|| panic!("Unknown label: {label}"));
-                opcodes.push(Opcode::Jump(addr));
+        let (label, make): (SourceLoc, fn(usize) -> Opcode) = match entry {
+            UnresolvedOpcode::Resolved(op) => {
+                opcodes.push(op);
+                continue;
             }
-            UnresolvedOpcode::JumpZeroLabel(label) => {
-                let addr = *labels
-                    .get(&label)
-                    .unwrap_or_else(|| panic!("Unknown label: {label}"));
-                opcodes.push(Opcode::JumpIfZero(addr));
+            UnresolvedOpcode::JumpLabel(label) => (label, Opcode::Jump),
+            UnresolvedOpcode::JumpZeroLabel(label) => (label, Opcode::JumpIfZero),
+            UnresolvedOpcode::JumpNotZeroLabel(label) => (label, Opcode::JumpIfNotZero),
+            UnresolvedOpcode::CallLabel(label, argc) => {
+                check_arity("CALL", label, argc, &functions, errors);
+                (label, Opcode::Call)
             }
-            UnresolvedOpcode::JumpNotZeroLabel(label) => {
-                let addr = *labels
-                    .get(&label)
-                    .unwrap_or_else(|| panic!("Unknown label: {label}"));
-                opcodes.push(Opcode::JumpIfNotZero(addr));
+            UnresolvedOpcode::TailCallLabel(label, argc) => {
+                check_arity("TAIL_CALL", label, argc, &functions, errors);
+                (label, Opcode::TailCall)
             }
-            UnresolvedOpcode::CallLabel(label) => {
-                let addr = *labels
-                    .get(&label)
-                    .unwrap_or_else(|| panic!("Unknown label: {label}"));
-                opcodes.push(Opcode::Call(addr));
+        };
+
+        match labels.get(label.token) {
+            Some(addr) => opcodes.push(make(*addr)),
+            None => {
+                errors.push(label.error(AssembleErrorKind::UnknownLabel(label.token.to_string())))
             }
         }
     }
 
-    //println!("{:?}", labels);
     let main_pc = *labels.get("fn_main").unwrap_or(&0);
 
-    (opcodes, main_pc)
+    Program {
+        entry: main_pc,
+        code: opcodes,
+        constants: vec![],
+        natives,
+        functions,
+        debug,
+    }
 }
 
-fn parse_constants(asm: &str) -> Vec<ConstantValue> {
-    asm.lines()
-        .filter(|x| x.trim_start().starts_with(".const"))
-        .map(|x| {
-            let no_comment = x.split(';').next().unwrap().trim();
-            no_comment
-        })
-        .map(|line| {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() < 3 {
-                panic!("Malformed .const line: {}", line);
+/// Declared constants keyed by their explicit index.
+/// A `None` value was declared but failed to parse, which has already been reported.
+type Constants = BTreeMap<u32, Option<ConstantValue>>;
+
+/// Most slots the constant pool can have. Unused indices below the highest one declared
+/// are filled with nil, so this keeps a lone huge index from taking all the memory.
+const MAX_CONSTANTS: u32 = 1 << 20;
+
+fn is_const_directive(line: &str) -> bool {
+    line.split_whitespace().next() == Some(".const")
+}
+
+fn parse_constants(lines: &[Line], errors: &mut Vec<AssembleError>) -> Constants {
+    let mut constants = Constants::new();
+
+    for &Line { number, text, code } in lines {
+        let line = code.trim();
+        if !is_const_directive(line) {
+            continue;
+        }
+
+        let loc = SourceLoc::new(number, text, line);
+        let rest = line[".const".len()..].trim_start();
+        let Some((index_str, value)) = rest.split_once(char::is_whitespace) else {
+            errors.push(loc.error(AssembleErrorKind::MalformedConst));
+            continue;
+        };
+        let value = value.trim();
+
+        let Ok(index) = index_str.parse::<u32>() else {
+            errors.push(
+                loc.at(index_str)
+                    .error(AssembleErrorKind::InvalidConstIndex(index_str.to_string())),
+            );
+            continue;
+        };
+        if index >= MAX_CONSTANTS {
+            errors.push(
+                loc.at(index_str)
+                    .error(AssembleErrorKind::ConstIndexOutOfRange(index)),
+            );
+            continue;
+        }
+
+        let value = match parse_const_value(value) {
+            Ok(x) => Some(x),
+            Err((offset, kind)) => {
+                errors.push(loc.at(&value[offset..]).error(kind));
+                None
             }
-            let value_str = parts[2..].join(" ");
-            parse_const_value(&value_str)
-        })
-        .collect()
+        };
+
+        if constants.insert(index, value).is_some() {
+            errors.push(
+                loc.at(index_str)
+                    .error(AssembleErrorKind::DuplicateConstIndex(index)),
+            );
+        }
+    }
+
+    constants
 }
 
-fn parse_const_value(value: &str) -> ConstantValue {
-    if let Ok(x) = value.parse::<i32>() {
-        return ConstantValue::Int(x);
+/// Lays the constants out so each one sits at its declared index.
+/// Gaps are never referenced (that is an assembly error), so they are filled with nil.
+fn constant_pool(constants: Constants) -> Vec<ConstantValue> {
+    let len = constants.keys().next_back().map_or(0, |x| *x as usize + 1);
+    let mut pool: Vec<ConstantValue> = (0..len).map(|_| ConstantValue::Nil).collect();
+    for (index, value) in constants {
+        pool[index as usize] = value.expect("invalid constants are reported before layout");
     }
-    if let Ok(x) = value.parse::<f32>() {
-        return ConstantValue::Float(x);
+    pool
+}
+
+/// Parses a constant's value, returning the byte offset of the problem on failure
+fn parse_const_value(value: &str) -> Result<ConstantValue, (usize, AssembleErrorKind)> {
+    if let Some(number) = parse_number(value) {
+        return number.map_err(|kind| (0, kind));
+    }
+
+    // the compiler writes booleans the way .NET formats them
+    match value {
+        "true" | "True" => return Ok(ConstantValue::Bool(true)),
+        "false" | "False" => return Ok(ConstantValue::Bool(false)),
+        "nil" => return Ok(ConstantValue::Nil),
+        _ => {}
     }
 
-    // Remove surrounding quotes if it's a string
-    if value.starts_with('"') && value.ends_with('"') {
-        return ConstantValue::Str(value[1..value.len() - 1].to_string());
+    if value.starts_with('"') || value.starts_with("r\"") || value.starts_with("r#") {
+        return parse_string_literal(value).map(ConstantValue::Str);
     }
 
-    panic!("Unable to parse constant: {:?}", value)
+    Err((0, AssembleErrorKind::InvalidConstValue(value.to_string())))
+}
+
+/// Parses an Int or Float literal, or returns `None` if `literal` doesn't start like one.
+/// Accepted forms, all optionally signed:
+/// - decimal Ints, and `0x` hex, `0b` binary and `0o` octal Ints
+/// - Floats with a decimal point or exponent (`2.5`, `.5`, `6.02e23`), `inf` and `nan`
+/// - `_` between digits, as in `1_000_000`
+/// - an `i64` or `f64` suffix picking the type, as in `3f64`; hex can only take `i64`
+fn parse_number(literal: &str) -> Option<Result<ConstantValue, AssembleErrorKind>> {
+    let invalid = || AssembleErrorKind::InvalidConstValue(literal.to_string());
+    let (sign, unsigned) = match literal.as_bytes().first() {
+        Some(b'-' | b'+') => literal.split_at(1),
+        _ => ("", literal),
+    };
+
+    match unsigned.to_ascii_lowercase().as_str() {
+        "inf" | "infinity" | "nan" => return Some(Ok(ConstantValue::Float(literal.parse().ok()?))),
+        _ => {}
+    }
+    let mut chars = unsigned.chars();
+    let starts_numeric = match chars.next() {
+        Some('.') => chars.next().is_some_and(|x| x.is_ascii_digit()),
+        first => first.is_some_and(|x| x.is_ascii_digit()),
+    };
+    if !starts_numeric {
+        return None;
+    }
+
+    let (radix, digits) = match unsigned.get(..2).map(|x| x.to_ascii_lowercase()).as_deref() {
+        Some("0x") => (16, &unsigned[2..]),
+        Some("0b") => (2, &unsigned[2..]),
+        Some("0o") => (8, &unsigned[2..]),
+        _ => (10, unsigned),
+    };
+    let (digits, float) = if let Some(x) = digits.strip_suffix("i64") {
+        (x, false)
+    } else if let Some(x) = digits.strip_suffix("f64").filter(|_| radix == 10) {
+        (x, true)
+    } else {
+        (digits, radix == 10 && digits.contains(['.', 'e', 'E']))
+    };
+    // separators only go between digits, and `from_str_radix` would accept a second sign
+    if digits.is_empty()
+        || digits.starts_with(['_', '+', '-'])
+        || digits.ends_with('_')
+        || digits.contains("__")
+    {
+        return Some(Err(invalid()));
+    }
+    let digits = format!("{sign}{}", digits.replace('_', ""));
+
+    Some(if float {
+        digits
+            .parse()
+            .map(ConstantValue::Float)
+            .map_err(|_| invalid())
+    } else {
+        i64::from_str_radix(&digits, radix)
+            .map(ConstantValue::Int)
+            .map_err(|err| match err.kind() {
+                IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                    AssembleErrorKind::IntOutOfRange(literal.to_string())
+                }
+                _ => invalid(),
+            })
+            .or_else(|err| big_int(&digits, radix).ok_or(err))
+    })
+}
+
+#[cfg(feature = "bigint")]
+fn big_int(digits: &str, radix: u32) -> Option<ConstantValue> {
+    use num_traits::Num;
+    num_bigint::BigInt::from_str_radix(digits, radix)
+        .ok()
+        .map(ConstantValue::BigInt)
+}
+
+/// Without the `bigint` feature, Ints past 64 bits are out of range
+#[cfg(not(feature = "bigint"))]
+fn big_int(_digits: &str, _radix: u32) -> Option<ConstantValue> {
+    None
+}
+
+/// Parses a string literal that makes up the whole of `literal`.
+/// Supports `"..."` with escapes and raw `r"..."` / `r#"..."#` strings, both of which may span lines.
+fn parse_string_literal(literal: &str) -> Result<String, (usize, AssembleErrorKind)> {
+    let invalid = || (0, AssembleErrorKind::InvalidConstValue(literal.to_string()));
+
+    if let Some(raw) = literal.strip_prefix('r') {
+        let hashes = raw.chars().take_while(|x| *x == '#').count();
+        let closing = format!("\"{}", "#".repeat(hashes));
+        let body = raw[hashes..].strip_prefix('"').ok_or_else(invalid)?;
+        let body = body.strip_suffix(closing.as_str()).ok_or_else(invalid)?;
+        if body.contains(closing.as_str()) {
+            return Err(invalid());
+        }
+        return Ok(body.replace("\r\n", "\n"));
+    }
+
+    let mut value = String::new();
+    let mut chars = literal.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                // the closing quote has to end the literal
+                return match chars.next() {
+                    None => Ok(value),
+                    Some(_) => Err(invalid()),
+                };
+            }
+            '\\' => {
+                let escape_error = |end: usize| {
+                    let end = literal[end..]
+                        .chars()
+                        .next()
+                        .map_or(end, |x| end + x.len_utf8());
+                    (
+                        i,
+                        AssembleErrorKind::InvalidEscape(literal[i..end].to_string()),
+                    )
+                };
+                let Some((j, escaped)) = chars.next() else {
+                    break;
+                };
+                match escaped {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    '0' => value.push('\0'),
+                    '\\' => value.push('\\'),
+                    '"' => value.push('"'),
+                    '\'' => value.push('\''),
+                    '\n' | '\r' => {
+                        // a backslash at the end of a line continues the string without the line break
+                        while chars.next_if(|(_, x)| x.is_whitespace()).is_some() {}
+                    }
+                    'u' => {
+                        let rest = &literal[j + 1..];
+                        let digits = rest
+                            .strip_prefix('{')
+                            .and_then(|x| x.split_once('}'))
+                            .map(|(digits, _)| digits)
+                            .ok_or_else(|| escape_error(j))?;
+                        let c = u32::from_str_radix(digits, 16)
+                            .ok()
+                            .filter(|_| (1..=6).contains(&digits.len()))
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| escape_error(j + digits.len() + 2))?;
+                        value.push(c);
+                        // skip past `{digits}`
+                        chars.nth(digits.len() + 1);
+                    }
+                    _ => return Err(escape_error(j)),
+                }
+            }
+            '\r' if chars.peek().is_some_and(|(_, x)| *x == '\n') => {}
+            _ => value.push(c),
+        }
+    }
+
+    Err((0, AssembleErrorKind::UnterminatedString))
+}
+
+/// Quotes and escapes `value` so that it can be written back out as an assembly string literal
+pub fn quote_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\0' => quoted.push_str("\\0"),
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
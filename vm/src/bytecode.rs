@@ -0,0 +1,584 @@
+//! Binary (`.lbc`) encoding of a `Program`, so programs can be shipped without their assembly.
+//!
+//! All integers are little-endian. The layout is:
+//!
+//! ```text
+//! magic        b"LEIA"
+//! version      u16
+//! features     u8 flags for what a reader must support: 1 for big integer constants
+//! entry        u32
+//! constants    u32 count, then per constant a u8 tag and its payload: an i64, an f64,
+//!              a string (as for natives), a u8 bool, nothing for nil or, with the
+//!              `bigint` feature, a u32 byte length and two's complement bytes
+//! natives      u32 count, then per native function name a u32 byte length and UTF-8 bytes
+//! functions    u32 count, then per `.fn` its name (as above) and u32 pc, arity and locals
+//! code         u32 count, then per instruction a u8 tag and its operand (if any) as a u32
+//! sections     u32 count, then per section a u8 id, a u32 byte length and the payload
+//! ```
+//!
+//! Sections hold optional debug information. Unknown section ids are skipped.
+
+use std::fmt::Display;
+
+use crate::instruction::{
+    ConstantIndex, ConstantValue, DebugInfo, Function, MAX_LOCALS, Opcode, Program,
+};
+
+pub const MAGIC: &[u8; 4] = b"LEIA";
+pub const FORMAT_VERSION: u16 = 5;
+
+/// Set when the constants include big integers, which need the `bigint` feature
+const FEATURE_BIGINT: u8 = 1;
+const KNOWN_FEATURES: u8 = FEATURE_BIGINT;
+
+const SECTION_LABELS: u8 = 1;
+const SECTION_LINES: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// The program needs a cargo feature this build of the VM was compiled without
+    NeedsFeature(&'static str),
+    Truncated {
+        offset: usize,
+    },
+    TrailingBytes {
+        offset: usize,
+    },
+    InvalidTag {
+        what: &'static str,
+        tag: u8,
+        offset: usize,
+    },
+    InvalidUtf8 {
+        offset: usize,
+    },
+    OutOfRange {
+        what: &'static str,
+        value: usize,
+        offset: usize,
+    },
+    /// A big integer constant small enough to be an Int, which the VM never produces
+    #[cfg(feature = "bigint")]
+    UnnormalizedBigInt {
+        offset: usize,
+    },
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "not a Leia bytecode file"),
+            BytecodeError::UnsupportedVersion(x) => write!(
+                f,
+                "bytecode format version {x} is not supported (expected {FORMAT_VERSION})"
+            ),
+            BytecodeError::NeedsFeature(x) => write!(
+                f,
+                "program needs the `{x}` feature, which this build of the VM doesn't have"
+            ),
+            BytecodeError::Truncated { offset } => {
+                write!(f, "file is truncated (ran out of bytes at offset {offset})")
+            }
+            BytecodeError::TrailingBytes { offset } => {
+                write!(
+                    f,
+                    "unexpected bytes after the end of the program at offset {offset}"
+                )
+            }
+            BytecodeError::InvalidTag { what, tag, offset } => {
+                write!(f, "invalid {what} tag {tag} at offset {offset}")
+            }
+            BytecodeError::InvalidUtf8 { offset } => {
+                write!(f, "string at offset {offset} is not valid UTF-8")
+            }
+            BytecodeError::OutOfRange {
+                what,
+                value,
+                offset,
+            } => write!(f, "{what} {value} at offset {offset} is out of range"),
+            #[cfg(feature = "bigint")]
+            BytecodeError::UnnormalizedBigInt { offset } => {
+                write!(f, "big integer at offset {offset} fits in an Int")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+impl Program {
+    /// Encodes the program, including its debug information, in the binary format.
+    /// The assembler bounds every operand and count to fit, so only a program built in
+    /// code with one past `u32::MAX` can't be encoded, which panics.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        // readers built without `bigint` can then refuse the file up front
+        let big = self.constants.iter().any(|x| match x {
+            #[cfg(feature = "bigint")]
+            ConstantValue::BigInt(_) => true,
+            _ => false,
+        });
+        out.push(if big { FEATURE_BIGINT } else { 0 });
+        write_u32(&mut out, self.entry);
+
+        write_u32(&mut out, self.constants.len());
+        for constant in &self.constants {
+            write_constant(&mut out, constant);
+        }
+
+        write_u32(&mut out, self.natives.len());
+        for name in &self.natives {
+            write_str(&mut out, name);
+        }
+
+        write_u32(&mut out, self.functions.len());
+        for function in &self.functions {
+            write_str(&mut out, &function.name);
+            write_u32(&mut out, function.pc);
+            write_u32(&mut out, function.arity);
+            write_u32(&mut out, function.locals);
+        }
+
+        write_u32(&mut out, self.code.len());
+        for opcode in &self.code {
+            write_opcode(&mut out, opcode);
+        }
+
+        let mut sections = Vec::new();
+        if !self.debug.labels.is_empty() {
+            let mut payload = Vec::new();
+            write_u32(&mut payload, self.debug.labels.len());
+            for (name, pc) in &self.debug.labels {
+                write_str(&mut payload, name);
+                write_u32(&mut payload, *pc);
+            }
+            sections.push((SECTION_LABELS, payload));
+        }
+        if !self.debug.lines.is_empty() {
+            let mut payload = Vec::new();
+            write_u32(&mut payload, self.debug.lines.len());
+            for line in &self.debug.lines {
+                write_u32(&mut payload, *line);
+            }
+            sections.push((SECTION_LINES, payload));
+        }
+
+        write_u32(&mut out, sections.len());
+        for (id, payload) in sections {
+            out.push(id);
+            write_u32(&mut out, payload.len());
+            out.extend_from_slice(&payload);
+        }
+
+        out
+    }
+
+    /// Decodes and validates a program written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, BytecodeError> {
+        let mut reader = Reader { bytes, offset: 0 };
+
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(BytecodeError::BadMagic);
+        }
+        let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+        let offset = reader.offset;
+        let features = reader.u8()?;
+        if features & !KNOWN_FEATURES != 0 {
+            return Err(BytecodeError::InvalidTag {
+                what: "feature flags",
+                tag: features,
+                offset,
+            });
+        }
+        if features & FEATURE_BIGINT != 0 && !cfg!(feature = "bigint") {
+            return Err(BytecodeError::NeedsFeature("bigint"));
+        }
+
+        let entry_offset = reader.offset;
+        let entry = reader.u32()?;
+
+        let count = reader.count()?;
+        let mut constants = Vec::with_capacity(count);
+        for _ in 0..count {
+            constants.push(reader.constant()?);
+        }
+
+        let count = reader.count()?;
+        let mut natives = Vec::with_capacity(count);
+        for _ in 0..count {
+            natives.push(reader.str()?);
+        }
+
+        let count = reader.count()?;
+        let mut functions = Vec::with_capacity(count);
+        for _ in 0..count {
+            let name = reader.str()?;
+            let offset = reader.offset;
+            let function = Function {
+                name,
+                pc: reader.u32()?,
+                arity: reader.u32()?,
+                locals: reader.u32()?,
+            };
+            functions.push((offset, function));
+        }
+
+        let count = reader.count()?;
+        let mut code = Vec::with_capacity(count);
+        for _ in 0..count {
+            let offset = reader.offset;
+            let opcode = reader.opcode()?;
+            validate_opcode(&opcode, &constants, natives.len(), count, offset)?;
+            code.push(opcode);
+        }
+
+        for (offset, function) in &functions {
+            if function.pc > code.len() {
+                return Err(BytecodeError::OutOfRange {
+                    what: "function start",
+                    value: function.pc,
+                    offset: *offset,
+                });
+            }
+            if function.locals > MAX_LOCALS {
+                return Err(BytecodeError::OutOfRange {
+                    what: "function locals",
+                    value: function.locals,
+                    offset: *offset + 8,
+                });
+            }
+            if function.locals < function.arity {
+                return Err(BytecodeError::OutOfRange {
+                    what: "function arity",
+                    value: function.arity,
+                    offset: *offset + 4,
+                });
+            }
+        }
+        let functions = functions.into_iter().map(|(_, x)| x).collect();
+
+        if entry > code.len() {
+            return Err(BytecodeError::OutOfRange {
+                what: "entry point",
+                value: entry,
+                offset: entry_offset,
+            });
+        }
+
+        let mut debug = DebugInfo::default();
+        for _ in 0..reader.count()? {
+            let id = reader.u8()?;
+            let len = reader.u32()?;
+            let mut section = Reader {
+                bytes: reader.take(len)?,
+                offset: reader.offset - len,
+            };
+            match id {
+                SECTION_LABELS => {
+                    for _ in 0..section.count()? {
+                        let name = section.str()?;
+                        let pc = section.u32()?;
+                        debug.labels.push((name, pc));
+                    }
+                }
+                SECTION_LINES => {
+                    for _ in 0..section.count()? {
+                        debug.lines.push(section.u32()?);
+                    }
+                }
+                _ => continue, // unknown sections are optional, skip them
+            }
+            section.finish()?;
+        }
+
+        reader.finish()?;
+
+        Ok(Program {
+            entry,
+            code,
+            constants,
+            natives,
+            functions,
+            debug,
+        })
+    }
+}
+
+// Constant tags
+const CONST_INT: u8 = 0;
+const CONST_FLOAT: u8 = 1;
+const CONST_STR: u8 = 2;
+const CONST_BOOL: u8 = 3;
+const CONST_NIL: u8 = 4;
+#[cfg(feature = "bigint")]
+const CONST_BIGINT: u8 = 5;
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("value does not fit in the bytecode format");
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    write_u32(out, value.len());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn write_constant(out: &mut Vec<u8>, constant: &ConstantValue) {
+    match constant {
+        ConstantValue::Int(x) => {
+            out.push(CONST_INT);
+            out.extend_from_slice(&x.to_le_bytes());
+        }
+        ConstantValue::Float(x) => {
+            out.push(CONST_FLOAT);
+            out.extend_from_slice(&x.to_le_bytes());
+        }
+        ConstantValue::Str(x) => {
+            out.push(CONST_STR);
+            write_str(out, x);
+        }
+        ConstantValue::Bool(x) => {
+            out.push(CONST_BOOL);
+            out.push(*x as u8);
+        }
+        ConstantValue::Nil => out.push(CONST_NIL),
+        #[cfg(feature = "bigint")]
+        ConstantValue::BigInt(x) => {
+            out.push(CONST_BIGINT);
+            let bytes = x.to_signed_bytes_le();
+            write_u32(out, bytes.len());
+            out.extend_from_slice(&bytes);
+        }
+    }
+}
+
+/// Tag and operand of every opcode. Tags are part of the file format and must never be reused.
+fn encode_opcode(opcode: &Opcode) -> (u8, Option<usize>) {
+    match opcode {
+        Opcode::Push(x) => (0, Some(x.0 as usize)),
+        Opcode::Pop => (1, None),
+        Opcode::Call(x) => (2, Some(*x)),
+        Opcode::Return => (3, None),
+        Opcode::Jump(x) => (4, Some(*x)),
+        Opcode::JumpIfZero(x) => (5, Some(*x)),
+        Opcode::JumpIfNotZero(x) => (6, Some(*x)),
+        Opcode::LoadLocal(x) => (7, Some(*x)),
+        Opcode::StoreLocal(x) => (8, Some(*x)),
+        Opcode::Increment(x) => (9, Some(*x)),
+        Opcode::Equals => (10, None),
+        Opcode::NotEqual => (11, None),
+        Opcode::GreaterThan => (12, None),
+        Opcode::GreaterThanEqual => (13, None),
+        Opcode::LessThan => (14, None),
+        Opcode::LessThanEqual => (15, None),
+        Opcode::Add => (16, None),
+        Opcode::Subtract => (17, None),
+        Opcode::Multiply => (18, None),
+        Opcode::Divide => (19, None),
+        Opcode::Modulo => (20, None),
+        Opcode::Print => (21, None),
+        Opcode::Halt => (22, None),
+        Opcode::CallNative(x) => (23, Some(*x)),
+        Opcode::ReturnVoid => (24, None),
+        Opcode::TailCall(x) => (25, Some(*x)),
+        Opcode::ToInt => (26, None),
+        Opcode::ToFloat => (27, None),
+        Opcode::ToStr => (28, None),
+        Opcode::Length => (29, None),
+        Opcode::Substring => (30, None),
+        Opcode::IndexOf => (31, None),
+        Opcode::Upper => (32, None),
+        Opcode::Lower => (33, None),
+        Opcode::Trim => (34, None),
+        Opcode::Split => (35, None),
+    }
+}
+
+fn write_opcode(out: &mut Vec<u8>, opcode: &Opcode) {
+    let (tag, operand) = encode_opcode(opcode);
+    out.push(tag);
+    if let Some(x) = operand {
+        write_u32(out, x);
+    }
+}
+
+/// Checks that operands refer to things that exist in the program
+fn validate_opcode(
+    opcode: &Opcode,
+    constants: &[ConstantValue],
+    natives: usize,
+    code_len: usize,
+    offset: usize,
+) -> Result<(), BytecodeError> {
+    let (what, value, limit) = match opcode {
+        Opcode::Push(x) => ("constant index", x.0 as usize, constants.len()),
+        Opcode::CallNative(x) => ("native function index", *x, natives),
+        Opcode::LoadLocal(x) | Opcode::StoreLocal(x) | Opcode::Increment(x) => {
+            ("local index", *x, MAX_LOCALS)
+        }
+        // jumping to the very end is allowed, it just ends the program
+        _ => match opcode.jump_target() {
+            Some(x) => ("jump target", x, code_len + 1),
+            None => return Ok(()),
+        },
+    };
+
+    if value >= limit {
+        return Err(BytecodeError::OutOfRange {
+            what,
+            value,
+            offset,
+        });
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize, // offset of `bytes` within the whole file, for error messages
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+        if self.bytes.len() < len {
+            return Err(BytecodeError::Truncated {
+                offset: self.offset + self.bytes.len(),
+            });
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        self.offset += len;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, BytecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    /// Reads a length prefix, rejecting counts that can't possibly fit in the remaining bytes
+    fn count(&mut self) -> Result<usize, BytecodeError> {
+        let count = self.u32()?;
+        if count > self.bytes.len() {
+            return Err(BytecodeError::Truncated {
+                offset: self.offset + self.bytes.len(),
+            });
+        }
+        Ok(count)
+    }
+
+    fn str(&mut self) -> Result<String, BytecodeError> {
+        let len = self.u32()?;
+        let offset = self.offset;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| BytecodeError::InvalidUtf8 { offset })
+    }
+
+    fn constant(&mut self) -> Result<ConstantValue, BytecodeError> {
+        let offset = self.offset;
+        Ok(match self.u8()? {
+            CONST_INT => ConstantValue::Int(i64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            CONST_FLOAT => {
+                ConstantValue::Float(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+            }
+            CONST_STR => ConstantValue::Str(self.str()?),
+            #[cfg(feature = "bigint")]
+            CONST_BIGINT => {
+                let len = self.u32()?;
+                let x = num_bigint::BigInt::from_signed_bytes_le(self.take(len)?);
+                if num_traits::ToPrimitive::to_i64(&x).is_some() {
+                    return Err(BytecodeError::UnnormalizedBigInt { offset });
+                }
+                ConstantValue::BigInt(x)
+            }
+            CONST_BOOL => match self.u8()? {
+                0 => ConstantValue::Bool(false),
+                1 => ConstantValue::Bool(true),
+                tag => {
+                    return Err(BytecodeError::InvalidTag {
+                        what: "boolean",
+                        tag,
+                        offset: offset + 1,
+                    });
+                }
+            },
+            CONST_NIL => ConstantValue::Nil,
+            tag => {
+                return Err(BytecodeError::InvalidTag {
+                    what: "constant",
+                    tag,
+                    offset,
+                });
+            }
+        })
+    }
+
+    /// Inverse of `encode_opcode`
+    fn opcode(&mut self) -> Result<Opcode, BytecodeError> {
+        let offset = self.offset;
+        Ok(match self.u8()? {
+            0 => Opcode::Push(ConstantIndex(self.u32()? as u32)),
+            1 => Opcode::Pop,
+            2 => Opcode::Call(self.u32()?),
+            3 => Opcode::Return,
+            4 => Opcode::Jump(self.u32()?),
+            5 => Opcode::JumpIfZero(self.u32()?),
+            6 => Opcode::JumpIfNotZero(self.u32()?),
+            7 => Opcode::LoadLocal(self.u32()?),
+            8 => Opcode::StoreLocal(self.u32()?),
+            9 => Opcode::Increment(self.u32()?),
+            10 => Opcode::Equals,
+            11 => Opcode::NotEqual,
+            12 => Opcode::GreaterThan,
+            13 => Opcode::GreaterThanEqual,
+            14 => Opcode::LessThan,
+            15 => Opcode::LessThanEqual,
+            16 => Opcode::Add,
+            17 => Opcode::Subtract,
+            18 => Opcode::Multiply,
+            19 => Opcode::Divide,
+            20 => Opcode::Modulo,
+            21 => Opcode::Print,
+            22 => Opcode::Halt,
+            23 => Opcode::CallNative(self.u32()?),
+            24 => Opcode::ReturnVoid,
+            25 => Opcode::TailCall(self.u32()?),
+            26 => Opcode::ToInt,
+            27 => Opcode::ToFloat,
+            28 => Opcode::ToStr,
+            29 => Opcode::Length,
+            30 => Opcode::Substring,
+            31 => Opcode::IndexOf,
+            32 => Opcode::Upper,
+            33 => Opcode::Lower,
+            34 => Opcode::Trim,
+            35 => Opcode::Split,
+            tag => {
+                return Err(BytecodeError::InvalidTag {
+                    what: "opcode",
+                    tag,
+                    offset,
+                });
+            }
+        })
+    }
+
+    fn finish(&self) -> Result<(), BytecodeError> {
+        if !self.bytes.is_empty() {
+            return Err(BytecodeError::TrailingBytes {
+                offset: self.offset,
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    instruction::{Opcode, Program},
+    vm::{StepOutcome, VM},
+};
+
+/// How many instructions are shown either side of the pc
+const CONTEXT_LINES: usize = 3;
+
+const HELP: &str = "commands:
+    break <label|pc>    set a breakpoint (b)
+    delete <label|pc>   remove a breakpoint (d)
+    step                execute one instruction (s)
+    next                step, running over any CALL (n)
+    finish              run until the current function returns (f)
+    continue            run until a breakpoint or the end (c)
+    list                show the instructions around the pc (l)
+    stack               show the operand stack
+    locals              show the current frame's locals
+    frames              show the call stack (bt)
+    quit                stop debugging (q)";
+
+/// A terminal debugger that drives a VM one instruction at a time
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<usize>,
+    finished: bool,
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            finished: false,
+        }
+    }
+
+    /// Reads commands from `input` until it ends or `quit` is given
+    pub fn run(&mut self, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        self.show_position(out)?;
+        write!(out, "(leia) ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                write!(out, "(leia) ")?;
+                out.flush()?;
+                continue;
+            };
+            let argument = words.next();
+
+            match command {
+                "b" | "break" => self.set_breakpoint(argument, true, out)?,
+                "d" | "delete" => self.set_breakpoint(argument, false, out)?,
+                "s" | "step" => self.resume(Resume::Step, out)?,
+                "n" | "next" => self.resume(Resume::Over, out)?,
+                "f" | "finish" => self.resume(Resume::Out, out)?,
+                "c" | "continue" => self.resume(Resume::Continue, out)?,
+                "l" | "list" => self.show_context(out)?,
+                "stack" => self.show_stack(out)?,
+                "locals" => self.show_locals(out)?,
+                "bt" | "frames" => self.show_frames(out)?,
+                "q" | "quit" => return Ok(()),
+                "h" | "help" => writeln!(out, "{HELP}")?,
+                _ => writeln!(out, "unknown command `{command}`, try `help`")?,
+            }
+            write!(out, "(leia) ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    fn set_breakpoint(
+        &mut self,
+        location: Option<&str>,
+        enable: bool,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let Some(location) = location else {
+            return writeln!(out, "expected a label or pc");
+        };
+        let program = self.vm.program();
+        let pc = match location.parse::<usize>() {
+            Ok(pc) if pc < program.code.len() => pc,
+            Ok(pc) => return writeln!(out, "pc {pc} is past the end of the program"),
+            Err(_) => match program.debug.label_pc(location) {
+                Some(pc) => pc,
+                None => return writeln!(out, "unknown label `{location}`"),
+            },
+        };
+
+        if enable {
+            self.breakpoints.insert(pc);
+            writeln!(out, "breakpoint at pc {pc}")
+        } else if self.breakpoints.remove(&pc) {
+            writeln!(out, "removed breakpoint at pc {pc}")
+        } else {
+            writeln!(out, "no breakpoint at pc {pc}")
+        }
+    }
+
+    fn resume(&mut self, how: Resume, out: &mut impl Write) -> io::Result<()> {
+        if self.finished {
+            return writeln!(out, "the program has finished");
+        }
+
+        let depth = self.vm.frames().len();
+        loop {
+            match self.vm.step() {
+                Ok(StepOutcome::Running) => {}
+                Ok(StepOutcome::Finished) => {
+                    self.finished = true;
+                    return writeln!(out, "program finished");
+                }
+                Err(err) => {
+                    self.finished = true;
+                    return writeln!(out, "{err}");
+                }
+            }
+
+            let depth_now = self.vm.frames().len();
+            let done = match how {
+                Resume::Step => true,
+                Resume::Over => depth_now <= depth,
+                Resume::Out => depth_now < depth,
+                Resume::Continue => false,
+            };
+            if done || self.breakpoints.contains(&self.vm.pc()) {
+                break;
+            }
+        }
+
+        self.show_position(out)
+    }
+
+    fn show_position(&self, out: &mut impl Write) -> io::Result<()> {
+        self.show_context(out)?;
+        self.show_stack(out)?;
+        self.show_locals(out)
+    }
+
+    fn show_context(&self, out: &mut impl Write) -> io::Result<()> {
+        let program = self.vm.program();
+        let pc = self.vm.pc();
+        let start = pc.saturating_sub(CONTEXT_LINES);
+        let end = (pc + CONTEXT_LINES + 1).min(program.code.len());
+
+        for idx in start..end {
+            for (name, _) in program.debug.labels.iter().filter(|(_, x)| *x == idx) {
+                writeln!(out, "      .{name}")?;
+            }
+            let marker = if idx == pc { "->" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&idx) {
+                '*'
+            } else {
+                ' '
+            };
+            writeln!(
+                out,
+                "{breakpoint}{marker} {idx:>4}  {}",
+                describe(program, &program.code[idx])
+            )?;
+        }
+        if pc >= program.code.len() {
+            writeln!(out, "-> {pc:>4}  <end of program>")?;
+        }
+        Ok(())
+    }
+
+    fn show_stack(&self, out: &mut impl Write) -> io::Result<()> {
+        let stack: Vec<String> = self.vm.stack().iter().map(|x| format!("{x:?}")).collect();
+        writeln!(out, "stack: [{}]", stack.join(", "))
+    }
+
+    fn show_locals(&self, out: &mut impl Write) -> io::Result<()> {
+        let locals: Vec<String> = self
+            .vm
+            .locals()
+            .iter()
+            .enumerate()
+            .map(|(idx, x)| match x {
+                Some(value) => format!("{idx}: {value:?}"),
+                None => format!("{idx}: uninitialized"),
+            })
+            .collect();
+        writeln!(out, "locals: [{}]", locals.join(", "))
+    }
+
+    fn show_frames(&self, out: &mut impl Write) -> io::Result<()> {
+        let program = self.vm.program();
+        writeln!(out, "call stack (innermost last):")?;
+        for (depth, frame) in self.vm.frames().iter().enumerate() {
+            if depth == 0 {
+                writeln!(out, "  #0 {}", function_name(program, program.entry))?;
+                continue;
+            }
+            writeln!(
+                out,
+                "  #{depth} {}, returns to pc {}, {} locals",
+                function_name(program, frame.function()),
+                frame.return_address() + 1,
+                frame.locals().len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+enum Resume {
+    Step,
+    Over,
+    Out,
+    Continue,
+}
+
+fn function_name(program: &Program, pc: usize) -> String {
+    match program.debug.label_at(pc) {
+        Some(name) => name.to_string(),
+        None => format!("<pc {pc}>"),
+    }
+}
+
+/// An instruction with its jump target named and its constant shown
+fn describe(program: &Program, opcode: &Opcode) -> String {
+    match opcode {
+        Opcode::Push(x) => match program.constants.get(x.0 as usize) {
+            Some(constant) => format!("{opcode} ; {constant}"),
+            None => opcode.to_string(),
+        },
+        Opcode::CallNative(x) => match program.natives.get(*x) {
+            Some(name) => format!("{} {name}", opcode.mnemonic()),
+            None => opcode.to_string(),
+        },
+        _ => match opcode.jump_target().and_then(|x| program.debug.label_at(x)) {
+            Some(label) => format!("{} {label}", opcode.mnemonic()),
+            None => opcode.to_string(),
+        },
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+
+use crate::instruction::{Opcode, Program};
+
+/// The label the assembler takes as the entry point
+const ENTRY_LABEL: &str = "fn_main";
+
+/// Turns a program back into assembly text.
+/// Assembling the output gives back the same program apart from its debug info: label
+/// names are kept where the debug info has them, but source line numbers and comments
+/// are lost, and the reassembled lines number the disassembly instead.
+pub fn disassemble(program: &Program) -> String {
+    let labels = label_names(program);
+    let mut out = String::new();
+
+    for (idx, constant) in program.constants.iter().enumerate() {
+        writeln!(out, ".const {idx} {constant}").unwrap();
+    }
+
+    for pc in 0..=program.code.len() {
+        if let Some(names) = labels.get(&pc) {
+            out.push('\n');
+            for name in names {
+                let comment = if name == ENTRY_LABEL {
+                    " ; entry point"
+                } else {
+                    ""
+                };
+                let declared = program
+                    .functions
+                    .iter()
+                    .find(|x| x.pc == pc && x.name == *name);
+                match declared {
+                    Some(x) => writeln!(out, ".fn {} {} {}{comment}", x.name, x.arity, x.locals),
+                    None => writeln!(out, ".{name}{comment}"),
+                }
+                .unwrap();
+            }
+        }
+
+        let Some(opcode) = program.code.get(pc) else {
+            break;
+        };
+        let target = |addr: &usize| labels[addr][0].as_str();
+        let line = match opcode {
+            Opcode::Call(x)
+            | Opcode::TailCall(x)
+            | Opcode::Jump(x)
+            | Opcode::JumpIfZero(x)
+            | Opcode::JumpIfNotZero(x) => {
+                format!("{} {}", opcode.mnemonic(), target(x))
+            }
+            Opcode::CallNative(x) => match program.natives.get(*x) {
+                Some(name) => format!("{} {name}", opcode.mnemonic()),
+                None => opcode.to_string(),
+            },
+            Opcode::Push(x) => match program.constants.get(x.0 as usize) {
+                Some(constant) => format!("{opcode} ; {constant}"),
+                None => opcode.to_string(),
+            },
+            _ => opcode.to_string(),
+        };
+        writeln!(out, "    {line}").unwrap();
+    }
+
+    out
+}
+
+/// Names every pc that needs a label: jump and call targets, the entry point
+/// (always `fn_main`), declared functions and anything the debug info already names.
+/// Existing names are kept, and generated names are used for the rest.
+fn label_names(program: &Program) -> BTreeMap<usize, Vec<String>> {
+    let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let mut taken = HashSet::new();
+
+    for function in &program.functions {
+        if taken.insert(function.name.clone()) {
+            labels
+                .entry(function.pc)
+                .or_default()
+                .push(function.name.clone());
+        }
+    }
+
+    for (name, pc) in &program.debug.labels {
+        // `fn_main` decides the entry point, so it can only go on the real one
+        let misplaced_entry = name == ENTRY_LABEL && *pc != program.entry;
+        if *pc <= program.code.len() && !misplaced_entry && taken.insert(name.clone()) {
+            labels.entry(*pc).or_default().push(name.clone());
+        }
+    }
+
+    if taken.insert(ENTRY_LABEL.to_string()) {
+        let names = labels.entry(program.entry).or_default();
+        names.insert(0, ENTRY_LABEL.to_string());
+    }
+
+    for pc in program.code.iter().filter_map(Opcode::jump_target) {
+        if labels.contains_key(&pc) {
+            continue;
+        }
+
+        let mut name = format!("L{pc}");
+        let mut suffix = 1;
+        while taken.contains(&name) {
+            name = format!("L{pc}_{suffix}");
+            suffix += 1;
+        }
+        taken.insert(name.clone());
+        labels.insert(pc, vec![name]);
+    }
+
+    labels
+}
@@ -1,10 +1,66 @@
 use std::fmt::Display;
 
-#[derive(Debug)]
+#[cfg(feature = "bigint")]
+use num_bigint::{BigInt, Sign};
+#[cfg(feature = "bigint")]
+use num_traits::{FromPrimitive, ToPrimitive, Zero};
+
+use crate::{assembler::quote_string, vm::VmErrorKind};
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Program {
     pub entry: usize,
     pub code: Vec<Opcode>,
     pub constants: Vec<ConstantValue>,
+    /// Names of the host functions called with `CALL_NATIVE`, indexed by its operand
+    pub natives: Vec<String>,
+    /// Functions declared with `.fn`, in source order
+    pub functions: Vec<Function>,
+    pub debug: DebugInfo,
+}
+
+/// Most local slots a frame can have, so local indices run from 0 to `MAX_LOCALS - 1`.
+/// The assembler and bytecode reader reject programs that use more.
+pub const MAX_LOCALS: usize = 1 << 16;
+
+/// A function declared with `.fn name arity locals`.
+/// `CALL` moves its arguments off the stack and into its first `arity` locals.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    /// The pc of its first instruction
+    pub pc: usize,
+    pub arity: usize,
+    /// How many local slots a call needs, arguments included
+    pub locals: usize,
+}
+
+/// Optional information about the source a program was assembled from.
+/// None of it is needed to run the program.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    /// Label names and the pc they mark, in source order
+    pub labels: Vec<(String, usize)>,
+    /// Source line of each instruction, empty if unknown
+    pub lines: Vec<usize>,
+}
+
+impl DebugInfo {
+    /// The first label marking `pc`, if any
+    pub fn label_at(&self, pc: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(_, x)| *x == pc)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The pc marked by the label `name`
+    pub fn label_pc(&self, name: &str) -> Option<usize> {
+        self.labels
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, pc)| *pc)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -16,12 +72,15 @@ pub enum Opcode {
 
     Pop, // pop the top value off the stack and dispose of it
 
-    Call(usize), // call a function
-    Return,      // Return from a function
+    Call(usize),       // call a function
+    CallNative(usize), // call a host function, by index into `Program::natives`
+    TailCall(usize),   // call a function in place of the current one, like `CALL` then `RET`
+    Return,            // Return from a function
+    ReturnVoid,        // Return from a function without a value
 
     Jump(usize),          // unconditional jump
-    JumpIfZero(usize),    // jump if popped value == 0
-    JumpIfNotZero(usize), // jump if popped value != 0
+    JumpIfZero(usize),    // jump if the top value is falsy
+    JumpIfNotZero(usize), // jump if the top value is truthy
 
     LoadLocal(usize),  // push from local variable slot
     StoreLocal(usize), // pop into local variable slot
@@ -40,88 +99,595 @@ pub enum Opcode {
     Divide,
     Modulo,
 
+    ToInt,   // convert the top value to an Int, truncating floats toward zero
+    ToFloat, // convert the top value to a Float
+    ToStr,   // convert the top value to the string `PRINT` would show
+
+    // String operations. Indexes count chars (Unicode scalar values), not bytes.
+    Length,    // number of chars in a Str
+    Substring, // the chars `start..end` of a Str, popping `end`, `start` and then the Str
+    IndexOf,   // char index where the top Str first occurs in the one below, or -1
+    Upper,     // a Str in upper case
+    Lower,     // a Str in lower case
+    Trim,      // a Str without leading and trailing whitespace
+    Split,     // a Str and separator become the text before and after the first separator
+
     Print,
     Halt,
 }
 
+impl Opcode {
+    /// The name the assembler knows this instruction by
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Opcode::Push(_) => "PUSH_CONST",
+            Opcode::Pop => "POP",
+            Opcode::Call(_) => "CALL",
+            Opcode::CallNative(_) => "CALL_NATIVE",
+            Opcode::TailCall(_) => "TAIL_CALL",
+            Opcode::Return => "RET",
+            Opcode::ReturnVoid => "RET_VOID",
+            Opcode::Jump(_) => "JUMP",
+            Opcode::JumpIfZero(_) => "JUMPZ",
+            Opcode::JumpIfNotZero(_) => "JUMPNZ",
+            Opcode::LoadLocal(_) => "LOAD_LOCAL",
+            Opcode::StoreLocal(_) => "STORE_LOCAL",
+            Opcode::Increment(_) => "INC",
+            Opcode::Equals => "EQ",
+            Opcode::NotEqual => "NEQ",
+            Opcode::GreaterThan => "GT",
+            Opcode::GreaterThanEqual => "GTE",
+            Opcode::LessThan => "LT",
+            Opcode::LessThanEqual => "LTE",
+            Opcode::Add => "ADD",
+            Opcode::Subtract => "SUB",
+            Opcode::Multiply => "MUL",
+            Opcode::Divide => "DIV",
+            Opcode::Modulo => "MOD",
+            Opcode::ToInt => "TO_INT",
+            Opcode::ToFloat => "TO_FLOAT",
+            Opcode::ToStr => "TO_STR",
+            Opcode::Length => "LEN",
+            Opcode::Substring => "SUBSTR",
+            Opcode::IndexOf => "INDEX_OF",
+            Opcode::Upper => "UPPER",
+            Opcode::Lower => "LOWER",
+            Opcode::Trim => "TRIM",
+            Opcode::Split => "SPLIT",
+            Opcode::Print => "PRINT",
+            Opcode::Halt => "HALT",
+        }
+    }
+
+    /// The address this instruction may transfer control to, if any
+    pub fn jump_target(&self) -> Option<usize> {
+        match self {
+            Opcode::Call(x)
+            | Opcode::TailCall(x)
+            | Opcode::Jump(x)
+            | Opcode::JumpIfZero(x)
+            | Opcode::JumpIfNotZero(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+/// Prints the instruction with raw numeric operands, e.g. `JUMP 12`
+impl Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Opcode::Push(x) => write!(f, "{} {}", self.mnemonic(), x.0),
+            Opcode::Call(x)
+            | Opcode::CallNative(x)
+            | Opcode::TailCall(x)
+            | Opcode::Jump(x)
+            | Opcode::JumpIfZero(x)
+            | Opcode::JumpIfNotZero(x)
+            | Opcode::LoadLocal(x)
+            | Opcode::StoreLocal(x)
+            | Opcode::Increment(x) => write!(f, "{} {}", self.mnemonic(), x),
+            _ => write!(f, "{}", self.mnemonic()),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Constant {
     index: ConstantIndex,
     value: ConstantValue,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ConstantValue {
-    Int(i32),
-    Float(f32),
+    Int(i64),
+    /// An Int literal too big for 64 bits
+    #[cfg(feature = "bigint")]
+    BigInt(BigInt),
+    Float(f64),
     Str(String),
+    Bool(bool),
+    Nil,
+}
+
+/// Prints the constant the way it is written in a `.const` directive
+impl Display for ConstantValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConstantValue::Int(x) => write!(f, "{x}"),
+            #[cfg(feature = "bigint")]
+            ConstantValue::BigInt(x) => write!(f, "{x}"),
+            // Debug keeps the decimal point so the value doesn't read back as an int
+            ConstantValue::Float(x) => write!(f, "{x:?}"),
+            ConstantValue::Str(x) => write!(f, "{}", quote_string(x)),
+            ConstantValue::Bool(x) => write!(f, "{x}"),
+            ConstantValue::Nil => write!(f, "nil"),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum LeiaValue {
-    Int(i32),
-    Float(f32),
+    Int(i64),
+    /// An Int outside the 64-bit range. Anything that fits is always an `Int`, so each
+    /// whole number has exactly one representation.
+    #[cfg(feature = "bigint")]
+    BigInt(BigInt),
+    Float(f64),
     Str(String),
+    Bool(bool),
+    Nil,
 }
 
 impl Display for LeiaValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             LeiaValue::Int(x) => write!(f, "{x}"),
-            LeiaValue::Float(x) => write!(f, "{x}"),
+            #[cfg(feature = "bigint")]
+            LeiaValue::BigInt(x) => write!(f, "{x}"),
+            // Debug keeps a decimal point or exponent and uses the fewest digits that
+            // read back as the same value, e.g. `1.0`, `0.1`, `1e21`, `NaN` and `inf`
+            LeiaValue::Float(x) => write!(f, "{x:?}"),
             LeiaValue::Str(x) => write!(f, "{x}"),
+            LeiaValue::Bool(x) => write!(f, "{x}"),
+            LeiaValue::Nil => write!(f, "nil"),
+        }
+    }
+}
+
+impl LeiaValue {
+    /// Name of the value's type, as shown in runtime errors
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LeiaValue::Int(_) => "Int",
+            #[cfg(feature = "bigint")]
+            LeiaValue::BigInt(_) => "Int",
+            LeiaValue::Float(_) => "Float",
+            LeiaValue::Str(_) => "Str",
+            LeiaValue::Bool(_) => "Bool",
+            LeiaValue::Nil => "Nil",
+        }
+    }
+
+    /// Truthiness used by conditional jumps:
+    /// `false`, `nil`, `0` and `0.0` are falsy, every other value (including any string) is truthy.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            LeiaValue::Int(x) => *x != 0,
+            // zero always fits in an Int
+            #[cfg(feature = "bigint")]
+            LeiaValue::BigInt(_) => true,
+            LeiaValue::Float(x) => *x != 0.0,
+            LeiaValue::Bool(x) => *x,
+            LeiaValue::Nil => false,
+            LeiaValue::Str(_) => true,
+        }
+    }
+
+    /// `TO_INT`: Floats are truncated toward zero, Bools become 0 or 1 and Strs are
+    /// parsed as decimal integers, ignoring surrounding whitespace. NaN, Floats outside
+    /// the Int range, unparsable strings and nil can't be converted.
+    /// With the `bigint` feature, Floats and strings outside the Int range become BigInts.
+    pub fn to_int(&self) -> Result<LeiaValue, VmErrorKind> {
+        #[cfg(feature = "bigint")]
+        {
+            let big = match self {
+                LeiaValue::BigInt(x) => Some(x.clone()),
+                LeiaValue::Float(x) => BigInt::from_f64(x.trunc()),
+                LeiaValue::Str(x) => x.trim().parse().ok(),
+                _ => None,
+            };
+            if let Some(x) = big {
+                return Ok(LeiaValue::from_big(x));
+            }
+        }
+        let converted = match self {
+            LeiaValue::Int(x) => Some(*x),
+            // `as` would saturate, so check the range first
+            LeiaValue::Float(x)
+                if x.trunc() >= i64::MIN as f64 && x.trunc() < -(i64::MIN as f64) =>
+            {
+                Some(*x as i64)
+            }
+            LeiaValue::Bool(x) => Some(*x as i64),
+            LeiaValue::Str(x) => x.trim().parse().ok(),
+            LeiaValue::Float(_) | LeiaValue::Nil => None,
+            #[cfg(feature = "bigint")]
+            LeiaValue::BigInt(_) => None,
+        };
+        converted
+            .map(LeiaValue::Int)
+            .ok_or_else(|| self.unconvertible("Int"))
+    }
+
+    /// `TO_FLOAT`: Ints become the nearest Float, Bools become 0.0 or 1.0 and Strs are
+    /// parsed as floats (including `inf` and `NaN`), ignoring surrounding whitespace.
+    /// Unparsable strings and nil can't be converted.
+    pub fn to_float(&self) -> Result<LeiaValue, VmErrorKind> {
+        let converted = match self {
+            LeiaValue::Int(x) => Some(*x as f64),
+            #[cfg(feature = "bigint")]
+            LeiaValue::BigInt(x) => Some(big_to_f64(x)),
+            LeiaValue::Float(x) => Some(*x),
+            LeiaValue::Bool(x) => Some(*x as i64 as f64),
+            LeiaValue::Str(x) => x.trim().parse().ok(),
+            LeiaValue::Nil => None,
+        };
+        converted
+            .map(LeiaValue::Float)
+            .ok_or_else(|| self.unconvertible("Float"))
+    }
+
+    /// `TO_STR`: every value converts to the text `PRINT` would show for it
+    pub fn to_str(&self) -> LeiaValue {
+        match self {
+            LeiaValue::Str(_) => self.clone(),
+            _ => LeiaValue::Str(self.to_string()),
+        }
+    }
+
+    fn unconvertible(&self, to: &'static str) -> VmErrorKind {
+        VmErrorKind::InvalidConversion {
+            value: self.clone(),
+            to,
+        }
+    }
+
+    fn mismatch(&self, other: &LeiaValue, operation: &'static str) -> VmErrorKind {
+        VmErrorKind::TypeMismatch {
+            operation,
+            left: self.type_name(),
+            right: other.type_name(),
         }
     }
 }
 
+#[cfg(feature = "bigint")]
+impl LeiaValue {
+    /// An Int if `x` fits in one, otherwise a BigInt
+    pub fn from_big(x: BigInt) -> LeiaValue {
+        match x.to_i64() {
+            Some(x) => LeiaValue::Int(x),
+            None => LeiaValue::BigInt(x),
+        }
+    }
+
+    fn to_big(&self) -> Option<BigInt> {
+        match self {
+            LeiaValue::Int(x) => Some(BigInt::from(*x)),
+            LeiaValue::BigInt(x) => Some(x.clone()),
+            _ => None,
+        }
+    }
+
+    /// Both operands as big integers when 64-bit arithmetic on them failed because it
+    /// overflowed or because one of them is already a BigInt
+    fn big_operands(
+        &self,
+        other: &LeiaValue,
+        result: &Result<LeiaValue, VmErrorKind>,
+    ) -> Option<(BigInt, BigInt)> {
+        match result {
+            Err(VmErrorKind::IntegerOverflow(_) | VmErrorKind::TypeMismatch { .. }) => {
+                Some((self.to_big()?, other.to_big()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The nearest Float, or infinity of the same sign past the Float range
+#[cfg(feature = "bigint")]
+fn big_to_f64(x: &BigInt) -> f64 {
+    x.to_f64().unwrap_or(match x.sign() {
+        Sign::Minus => f64::NEG_INFINITY,
+        _ => f64::INFINITY,
+    })
+}
+
+// Int arithmetic comes in two flavours: the plain methods fail with `IntegerOverflow`
+// when the result doesn't fit in an Int, and the `wrapping_` ones wrap around in two's
+// complement. `VmConfig::arithmetic` picks which the VM uses.
+//
+// An Int meeting a Float is converted to the nearest Float first, so `1 + 2.5` is `3.5`
+// and `3 < 2.5` is `false`. Two Ints stay Ints.
+//
+// With the `bigint` feature, the plain methods redo an overflowing operation with big
+// integers instead of failing, and either flavour works on BigInts, which never wrap.
 macro_rules! impl_arith_op {
-    ($name:ident, $symbol:tt, $err:expr) => {
-        pub fn $name(self, other: LeiaValue) -> LeiaValue {
-            match (self, other) {
-                (LeiaValue::Int(a), LeiaValue::Int(b)) => LeiaValue::Int(a $symbol b),
-                (LeiaValue::Float(a), LeiaValue::Float(b)) => LeiaValue::Float(a $symbol b),
-                _ => panic!($err),
+    ($name:ident, $wrapping_name:ident, $symbol:tt, $checked:expr, $wrapping:expr, $op:expr) => {
+        pub fn $name(&self, other: &LeiaValue) -> Result<LeiaValue, VmErrorKind> {
+            let result = self.arithmetic(
+                other,
+                $op,
+                |a, b| $checked(a, b).ok_or(VmErrorKind::IntegerOverflow($op)),
+                |a, b| a $symbol b,
+            );
+            #[cfg(feature = "bigint")]
+            if let Some((a, b)) = self.big_operands(other, &result) {
+                return Ok(LeiaValue::from_big(a $symbol b));
             }
+            result
+        }
+
+        pub fn $wrapping_name(&self, other: &LeiaValue) -> Result<LeiaValue, VmErrorKind> {
+            let result =
+                self.arithmetic(other, $op, |a, b| Ok($wrapping(a, b)), |a, b| a $symbol b);
+            #[cfg(feature = "bigint")]
+            if let Some((a, b)) = self.big_operands(other, &result) {
+                return Ok(LeiaValue::from_big(a $symbol b));
+            }
+            result
+        }
+    };
+    // Int division and modulo by zero are runtime errors in either flavour.
+    // Once a Float is involved they follow IEEE 754 instead, giving infinity or NaN.
+    (divisor $name:ident, $wrapping_name:ident, $symbol:tt, $checked:expr, $wrapping:expr, $op:expr) => {
+        pub fn $name(&self, other: &LeiaValue) -> Result<LeiaValue, VmErrorKind> {
+            let result = self.arithmetic(
+                other,
+                $op,
+                |a, b| match b {
+                    0 => Err(VmErrorKind::DivisionByZero),
+                    _ => $checked(a, b).ok_or(VmErrorKind::IntegerOverflow($op)),
+                },
+                |a, b| a $symbol b,
+            );
+            #[cfg(feature = "bigint")]
+            if let Some((a, b)) = self.big_operands(other, &result) {
+                return match b.is_zero() {
+                    true => Err(VmErrorKind::DivisionByZero),
+                    false => Ok(LeiaValue::from_big(a $symbol b)),
+                };
+            }
+            result
+        }
+
+        pub fn $wrapping_name(&self, other: &LeiaValue) -> Result<LeiaValue, VmErrorKind> {
+            let result = self.arithmetic(
+                other,
+                $op,
+                |a, b| match b {
+                    0 => Err(VmErrorKind::DivisionByZero),
+                    _ => Ok($wrapping(a, b)),
+                },
+                |a, b| a $symbol b,
+            );
+            #[cfg(feature = "bigint")]
+            if let Some((a, b)) = self.big_operands(other, &result) {
+                return match b.is_zero() {
+                    true => Err(VmErrorKind::DivisionByZero),
+                    false => Ok(LeiaValue::from_big(a $symbol b)),
+                };
+            }
+            result
         }
     };
 }
 
 macro_rules! impl_cmp_op {
-    ($name:ident, $symbol:tt, $err:expr) => {
-        pub fn $name(self, other: LeiaValue) -> LeiaValue {
+    ($name:ident, $symbol:tt, $op:expr) => {
+        pub fn $name(&self, other: &LeiaValue) -> Result<LeiaValue, VmErrorKind> {
+            match (self, other) {
+                (LeiaValue::Int(a), LeiaValue::Int(b)) => Ok(LeiaValue::Bool(a $symbol b)),
+                (LeiaValue::Float(a), LeiaValue::Float(b)) => Ok(LeiaValue::Bool(a $symbol b)),
+                (LeiaValue::Int(a), LeiaValue::Float(b)) => Ok(LeiaValue::Bool((*a as f64) $symbol *b)),
+                (LeiaValue::Float(a), LeiaValue::Int(b)) => Ok(LeiaValue::Bool(*a $symbol (*b as f64))),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::BigInt(a), LeiaValue::BigInt(b)) => Ok(LeiaValue::Bool(a $symbol b)),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::BigInt(a), LeiaValue::Int(b)) => Ok(LeiaValue::Bool(*a $symbol BigInt::from(*b))),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::Int(a), LeiaValue::BigInt(b)) => Ok(LeiaValue::Bool(BigInt::from(*a) $symbol *b)),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::BigInt(a), LeiaValue::Float(b)) => Ok(LeiaValue::Bool(big_to_f64(a) $symbol *b)),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::Float(a), LeiaValue::BigInt(b)) => Ok(LeiaValue::Bool(*a $symbol big_to_f64(b))),
+                // strings are ordered char by char, by Unicode code point
+                (LeiaValue::Str(a), LeiaValue::Str(b)) => Ok(LeiaValue::Bool(a $symbol b)),
+                _ => Err(self.mismatch(other, $op)),
+            }
+        }
+    };
+    // booleans and nil can only be compared for (in)equality, not ordered,
+    // and equality never fails
+    (equality $name:ident, $symbol:tt) => {
+        pub fn $name(&self, other: &LeiaValue) -> Result<LeiaValue, VmErrorKind> {
             match (self, other) {
-                (LeiaValue::Int(a), LeiaValue::Int(b)) => {
-                    LeiaValue::Int((a $symbol b) as i32)
-                }
-                (LeiaValue::Float(a), LeiaValue::Float(b)) => {
-                    LeiaValue::Int((a $symbol b) as i32)
-                }
-                _ => panic!($err),
+                (LeiaValue::Int(a), LeiaValue::Int(b)) => Ok(LeiaValue::Bool(a $symbol b)),
+                (LeiaValue::Float(a), LeiaValue::Float(b)) => Ok(LeiaValue::Bool(a $symbol b)),
+                (LeiaValue::Int(a), LeiaValue::Float(b)) => Ok(LeiaValue::Bool((*a as f64) $symbol *b)),
+                (LeiaValue::Float(a), LeiaValue::Int(b)) => Ok(LeiaValue::Bool(*a $symbol (*b as f64))),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::BigInt(a), LeiaValue::BigInt(b)) => Ok(LeiaValue::Bool(a $symbol b)),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::BigInt(a), LeiaValue::Int(b)) => Ok(LeiaValue::Bool(*a $symbol BigInt::from(*b))),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::Int(a), LeiaValue::BigInt(b)) => Ok(LeiaValue::Bool(BigInt::from(*a) $symbol *b)),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::BigInt(a), LeiaValue::Float(b)) => Ok(LeiaValue::Bool(big_to_f64(a) $symbol *b)),
+                #[cfg(feature = "bigint")]
+                (LeiaValue::Float(a), LeiaValue::BigInt(b)) => Ok(LeiaValue::Bool(*a $symbol big_to_f64(b))),
+                (LeiaValue::Str(a), LeiaValue::Str(b)) => Ok(LeiaValue::Bool(a $symbol b)),
+                (LeiaValue::Bool(a), LeiaValue::Bool(b)) => Ok(LeiaValue::Bool(a $symbol b)),
+                // nil is only ever equal to itself
+                (LeiaValue::Nil, LeiaValue::Nil) => Ok(LeiaValue::Bool(() $symbol ())),
+                // values of different types are never equal, so `x == nil` works for any `x`
+                _ => Ok(LeiaValue::Bool(self.type_name() $symbol other.type_name())),
             }
         }
     };
 }
 
+#[allow(clippy::should_implement_trait)]
 impl LeiaValue {
-    impl_arith_op!(add, +, "Invalid types for addition");
-    impl_arith_op!(sub, -, "Invalid types for subtraction");
-    impl_arith_op!(mul, *, "Invalid types for multiplication");
-    impl_arith_op!(div, /, "Invalid types for division");
-    impl_arith_op!(modulo, %, "Invalid types for division");
-
-    // You can still write specialized ones by hand, like string concatenation
-    pub fn add_string(self, other: LeiaValue) -> LeiaValue {
+    impl_arith_op!(add, wrapping_add, +, i64::checked_add, i64::wrapping_add, "addition");
+    impl_arith_op!(sub, wrapping_sub, -, i64::checked_sub, i64::wrapping_sub, "subtraction");
+    impl_arith_op!(mul, wrapping_mul, *, i64::checked_mul, i64::wrapping_mul, "multiplication");
+    // Division truncates toward zero, and `i64::MIN / -1` overflows.
+    impl_arith_op!(divisor div, wrapping_div, /, i64::checked_div, i64::wrapping_div, "division");
+    // The remainder takes the sign of the dividend, so `-7 % 3` is `-1` and `7 % -3` is `1`.
+    // `i64::MIN % -1` is 0, which fits, so it never overflows.
+    impl_arith_op!(
+        divisor modulo,
+        wrapping_modulo,
+        %,
+        |a: i64, b: i64| Some(a.wrapping_rem(b)),
+        i64::wrapping_rem,
+        "modulo"
+    );
+
+    fn arithmetic(
+        &self,
+        other: &LeiaValue,
+        operation: &'static str,
+        int: impl Fn(i64, i64) -> Result<i64, VmErrorKind>,
+        float: impl Fn(f64, f64) -> f64,
+    ) -> Result<LeiaValue, VmErrorKind> {
+        match (self, other) {
+            (LeiaValue::Int(a), LeiaValue::Int(b)) => int(*a, *b).map(LeiaValue::Int),
+            (LeiaValue::Float(a), LeiaValue::Float(b)) => Ok(LeiaValue::Float(float(*a, *b))),
+            (LeiaValue::Int(a), LeiaValue::Float(b)) => Ok(LeiaValue::Float(float(*a as f64, *b))),
+            (LeiaValue::Float(a), LeiaValue::Int(b)) => Ok(LeiaValue::Float(float(*a, *b as f64))),
+            #[cfg(feature = "bigint")]
+            (LeiaValue::BigInt(a), LeiaValue::Float(b)) => {
+                Ok(LeiaValue::Float(float(big_to_f64(a), *b)))
+            }
+            #[cfg(feature = "bigint")]
+            (LeiaValue::Float(a), LeiaValue::BigInt(b)) => {
+                Ok(LeiaValue::Float(float(*a, big_to_f64(b))))
+            }
+            _ => Err(self.mismatch(other, operation)),
+        }
+    }
+
+    /// `ADD` on two Strs joins them
+    pub fn concat(&self, other: &LeiaValue) -> Result<LeiaValue, VmErrorKind> {
         match (self, other) {
-            (LeiaValue::Str(a), LeiaValue::Str(b)) => LeiaValue::Str(a + &b),
-            _ => panic!("Invalid types for string addition"),
+            (LeiaValue::Str(a), LeiaValue::Str(b)) => Ok(LeiaValue::Str(a.clone() + b)),
+            _ => Err(self.mismatch(other, "concatenation")),
+        }
+    }
+
+    impl_cmp_op!(gt, >, "greater-than comparison");
+    impl_cmp_op!(lt, <, "less-than comparison");
+    impl_cmp_op!(lte, <=, "less-than-equal comparison");
+    impl_cmp_op!(gte, >=, "greater-than-equal comparison");
+    impl_cmp_op!(equality eq, ==);
+    impl_cmp_op!(equality neq, !=);
+    impl_cmp_op!(equality ne, !=);
+}
+
+// String operations count chars (Unicode scalar values) rather than bytes, so every
+// index lands on a char boundary and `LEN` agrees with `SUBSTR` and `INDEX_OF`.
+impl LeiaValue {
+    /// `LEN`: the number of chars in a Str
+    pub fn length(&self) -> Result<LeiaValue, VmErrorKind> {
+        let x = self.as_str("length")?;
+        Ok(LeiaValue::Int(x.chars().count() as i64))
+    }
+
+    /// `SUBSTR`: the chars from `start` up to but not including `end`
+    pub fn substring(&self, start: &LeiaValue, end: &LeiaValue) -> Result<LeiaValue, VmErrorKind> {
+        let x = self.as_str("substring")?;
+        let len = x.chars().count();
+        match (char_index(start)?, char_index(end)?) {
+            (Some(from), Some(to)) if from <= to && to <= len => Ok(LeiaValue::Str(
+                x.chars().skip(from).take(to - from).collect(),
+            )),
+            _ => Err(VmErrorKind::SubstringOutOfBounds {
+                start: start.clone(),
+                end: end.clone(),
+                len,
+            }),
+        }
+    }
+
+    /// `INDEX_OF`: the char index where `needle` first occurs, or -1 if it doesn't
+    pub fn index_of(&self, needle: &LeiaValue) -> Result<LeiaValue, VmErrorKind> {
+        match (self, needle) {
+            (LeiaValue::Str(x), LeiaValue::Str(needle)) => Ok(LeiaValue::Int(
+                x.find(needle.as_str())
+                    .map_or(-1, |byte| x[..byte].chars().count() as i64),
+            )),
+            _ => Err(self.mismatch(needle, "index-of")),
+        }
+    }
+
+    /// `UPPER`: upper case by the Unicode rules, which can change the length (`ß` becomes `SS`)
+    pub fn upper(&self) -> Result<LeiaValue, VmErrorKind> {
+        Ok(LeiaValue::Str(self.as_str("upper case")?.to_uppercase()))
+    }
+
+    /// `LOWER`: lower case by the Unicode rules
+    pub fn lower(&self) -> Result<LeiaValue, VmErrorKind> {
+        Ok(LeiaValue::Str(self.as_str("lower case")?.to_lowercase()))
+    }
+
+    /// `TRIM`: drops leading and trailing Unicode whitespace
+    pub fn trim(&self) -> Result<LeiaValue, VmErrorKind> {
+        Ok(LeiaValue::Str(self.as_str("trim")?.trim().to_string()))
+    }
+
+    /// `SPLIT`: the text before and after the first `separator`, or the whole Str and
+    /// nil if there is none. Splitting the rest again walks through every part.
+    pub fn split(&self, separator: &LeiaValue) -> Result<(LeiaValue, LeiaValue), VmErrorKind> {
+        match (self, separator) {
+            (LeiaValue::Str(_), LeiaValue::Str(separator)) if separator.is_empty() => {
+                Err(VmErrorKind::EmptySeparator)
+            }
+            (LeiaValue::Str(x), LeiaValue::Str(separator)) => {
+                Ok(match x.split_once(separator.as_str()) {
+                    Some((before, after)) => (
+                        LeiaValue::Str(before.to_string()),
+                        LeiaValue::Str(after.to_string()),
+                    ),
+                    None => (self.clone(), LeiaValue::Nil),
+                })
+            }
+            _ => Err(self.mismatch(separator, "split")),
+        }
+    }
+
+    fn as_str(&self, operation: &'static str) -> Result<&str, VmErrorKind> {
+        match self {
+            LeiaValue::Str(x) => Ok(x),
+            _ => Err(VmErrorKind::InvalidOperand {
+                operation,
+                value: self.type_name(),
+            }),
         }
     }
+}
 
-    impl_cmp_op!(gt, >, "Invalid types for greater-than comparison");
-    impl_cmp_op!(lt, <, "Invalid types for less-than comparison");
-    impl_cmp_op!(lte, <=, "Invalid types for less-than-equal comparison");
-    impl_cmp_op!(gte, >=, "Invalid types for greater-than-equal comparison");
-    impl_cmp_op!(eq, ==, "Invalid types for equality comparison");
-    impl_cmp_op!(neq, !=, "Invalid types for equality comparison");
-    impl_cmp_op!(ne, !=, "Invalid types for inequality comparison");
+/// A char index for `SUBSTR`, or `None` if it can't be in bounds
+fn char_index(value: &LeiaValue) -> Result<Option<usize>, VmErrorKind> {
+    match value {
+        LeiaValue::Int(x) => Ok(usize::try_from(*x).ok()),
+        #[cfg(feature = "bigint")]
+        LeiaValue::BigInt(_) => Ok(None),
+        _ => Err(VmErrorKind::InvalidOperand {
+            operation: "substring",
+            value: value.type_name(),
+        }),
+    }
 }
@@ -1,3 +1,10 @@
 pub mod assembler;
+pub mod bytecode;
+pub mod debugger;
+pub mod disassembler;
 pub mod instruction;
+pub mod optimizer;
+pub mod profiler;
+pub mod trace;
+pub mod verifier;
 pub mod vm;
@@ -1,13 +1,244 @@
+use std::io::{Read, Write};
+use std::process::ExitCode;
 use std::time::Instant;
 
-use vm::assembler::parse_assembly;
+use vm::assembler::try_parse_assembly;
+use vm::bytecode::MAGIC;
+use vm::debugger::Debugger;
+use vm::disassembler::disassemble;
+use vm::instruction::Program;
+use vm::optimizer;
+use vm::profiler::Profiler;
+use vm::trace;
+use vm::verifier::verify;
 use vm::vm::VM;
 
-fn main() {
-    let asm_text = include_str!("../../test.asm");
-    let asm = parse_assembly(asm_text);
-    let mut vm = VM::new(asm);
+const USAGE: &str = "usage: leia <command> [options] [file]
+
+commands:
+    run <file>              run an assembly or bytecode program
+    asm <file> -o <out>     assemble a program to bytecode (`-o -` writes to stdout)
+    disasm <file>           print a program as assembly
+    check <file>            assemble and verify a program without running it
+    debug <file>            step through a program, reading commands from stdin
+
+options:
+    --time                  print how long the command took
+    --tail-calls            rewrite `CALL f` followed by `RET` into `TAIL_CALL f`
+    --trace <format>        `run` writes a trace to stderr, as `human`, `json` (lines)
+                            or `chrome` (trace events)
+    --profile               `run` prints the hottest functions, opcodes and pcs to stderr
+    --folded <file>         `run` writes folded call stacks for flamegraph tools
+
+Reads from stdin when the file is `-` or left out.";
+
+enum Command {
+    Help,
+    Run,
+    Asm { output: String },
+    Disasm,
+    Check,
+    Debug,
+}
+
+struct Args {
+    command: Command,
+    input: Option<String>,
+    time: bool,
+    tail_calls: bool,
+    trace: Option<String>,
+    profile: bool,
+    folded: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let name = args.next().ok_or("missing command")?;
+    let mut input = None;
+    let mut output = None;
+    let mut time = false;
+    let mut tail_calls = false;
+    let mut trace = None;
+    let mut profile = false;
+    let mut folded = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--time" => time = true,
+            "--tail-calls" => tail_calls = true,
+            "--trace" => match args.next() {
+                Some(format) if ["human", "json", "chrome"].contains(&format.as_str()) => {
+                    trace = Some(format)
+                }
+                _ => return Err("`--trace` needs a format: human, json or chrome".to_string()),
+            },
+            "--profile" => profile = true,
+            "--folded" => folded = Some(args.next().ok_or("`--folded` needs a file name")?),
+            "-o" => output = Some(args.next().ok_or("`-o` needs a file name")?),
+            "-" => input = None,
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(format!("unexpected argument `{arg}`")),
+        }
+    }
+
+    let command = match name.as_str() {
+        "help" | "-h" | "--help" => Command::Help,
+        "run" => Command::Run,
+        "asm" => Command::Asm {
+            output: output
+                .take()
+                .ok_or("`asm` needs an output file, given with `-o`")?,
+        },
+        "disasm" => Command::Disasm,
+        "check" => Command::Check,
+        "debug" => Command::Debug,
+        _ => return Err(format!("unknown command `{name}`")),
+    };
+    if output.is_some() && !matches!(command, Command::Asm { .. }) {
+        return Err(format!("`-o` is only used by `asm`, not `{name}`"));
+    }
+    if (trace.is_some() || profile || folded.is_some()) && !matches!(command, Command::Run) {
+        return Err(format!(
+            "`--trace`, `--profile` and `--folded` are only used by `run`, not `{name}`"
+        ));
+    }
+    if trace.is_some() && (profile || folded.is_some()) {
+        return Err("tracing and profiling can't be used together".to_string());
+    }
+    if matches!(command, Command::Debug) && input.is_none() {
+        return Err("`debug` reads commands from stdin, so it needs a file".to_string());
+    }
+
+    Ok(Args {
+        command,
+        input,
+        time,
+        tail_calls,
+        trace,
+        profile,
+        folded,
+    })
+}
+
+/// Reads a program from a file or stdin, as bytecode if it starts with the
+/// bytecode magic and as assembly otherwise. Errors are printed before returning.
+fn load(input: Option<&str>) -> Result<Program, ()> {
+    let name = input.unwrap_or("<stdin>");
+    let mut bytes = Vec::new();
+    let read = match input {
+        Some(path) => std::fs::read(path).map(|x| bytes = x),
+        None => std::io::stdin().read_to_end(&mut bytes).map(|_| ()),
+    };
+    if let Err(err) = read {
+        eprintln!("{name}: {err}");
+        return Err(());
+    }
+
+    if bytes.starts_with(MAGIC) {
+        return Program::from_bytes(&bytes).map_err(|err| eprintln!("{name}: {err}"));
+    }
+
+    let Ok(asm) = String::from_utf8(bytes) else {
+        eprintln!("{name}: not valid UTF-8 assembly or bytecode");
+        return Err(());
+    };
+    try_parse_assembly(&asm).map_err(|errors| {
+        for err in &errors {
+            eprintln!("{err}\n");
+        }
+        eprintln!("{name}: {} error(s) found", errors.len());
+    })
+}
+
+fn execute(args: &Args) -> Result<(), ()> {
+    if let Command::Help = args.command {
+        println!("{USAGE}");
+        return Ok(());
+    }
+    let mut program = load(args.input.as_deref())?;
+    if args.tail_calls {
+        optimizer::tail_calls(&mut program);
+    }
+
+    match &args.command {
+        Command::Help => {}
+        Command::Run => {
+            for finding in verify(&program) {
+                eprintln!("warning: {finding}");
+            }
+            let mut vm = VM::new(program);
+            let stderr = std::io::stderr();
+            match args.trace.as_deref() {
+                Some("human") => vm.set_trace_handler(trace::human_readable(stderr)),
+                Some("json") => vm.set_trace_handler(trace::json_lines(stderr)),
+                Some("chrome") => vm.set_trace_handler(trace::chrome_trace(stderr, vm.program())),
+                _ => {}
+            }
+            let profiler =
+                (args.profile || args.folded.is_some()).then(|| Profiler::attach(&mut vm));
+
+            let result = vm.run().map_err(|err| eprintln!("{err}"));
+
+            if let Some(profiler) = profiler {
+                let profile = profiler.profile();
+                if args.profile {
+                    eprint!("{}", profile.report());
+                }
+                if let Some(path) = &args.folded {
+                    std::fs::write(path, profile.folded_stacks())
+                        .map_err(|err| eprintln!("{path}: {err}"))?;
+                }
+            }
+            result?;
+        }
+        Command::Asm { output } => {
+            let bytes = program.to_bytes();
+            let written = if output == "-" {
+                std::io::stdout().write_all(&bytes)
+            } else {
+                std::fs::write(output, bytes)
+            };
+            written.map_err(|err| eprintln!("{output}: {err}"))?;
+        }
+        Command::Disasm => print!("{}", disassemble(&program)),
+        Command::Check => {
+            let findings = verify(&program);
+            for finding in &findings {
+                eprintln!("{finding}");
+            }
+            if !findings.is_empty() {
+                return Err(());
+            }
+        }
+        Command::Debug => {
+            let mut debugger = Debugger::new(VM::new(program));
+            let mut stdout = std::io::stdout();
+            debugger
+                .run(std::io::stdin().lock(), &mut stdout)
+                .map_err(|err| eprintln!("{err}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("error: {err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
     let start = Instant::now();
-    vm.run();
-    println!("elapsed: {:?}", start.elapsed());
+    let result = execute(&args);
+    if args.time {
+        eprintln!("elapsed: {:?}", start.elapsed());
+    }
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(()) => ExitCode::FAILURE,
+    }
 }
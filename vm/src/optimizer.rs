@@ -0,0 +1,31 @@
+use crate::instruction::{Opcode, Program};
+
+/// Rewrites every `CALL f` whose next step is a `RET` into `TAIL_CALL f`, so the
+/// called function reuses the caller's frame. Unconditional jumps between the two are
+/// followed, since compilers often share one `RET` between branches. The `RET` itself
+/// is kept, so other paths that reach it still return. Returns how many calls changed.
+pub fn tail_calls(program: &mut Program) -> usize {
+    let mut rewritten = 0;
+    for pc in 0..program.code.len() {
+        if let Opcode::Call(target) = program.code[pc]
+            && returns_next(program, pc + 1)
+        {
+            program.code[pc] = Opcode::TailCall(target);
+            rewritten += 1;
+        }
+    }
+    rewritten
+}
+
+/// Whether execution starting at `pc` reaches a `RET` without doing anything else
+fn returns_next(program: &Program, mut pc: usize) -> bool {
+    // a chain of jumps longer than the program must be a loop
+    for _ in 0..program.code.len() {
+        match program.code.get(pc) {
+            Some(Opcode::Jump(target)) => pc = *target,
+            Some(Opcode::Return) => return true,
+            _ => return false,
+        }
+    }
+    false
+}
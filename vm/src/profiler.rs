@@ -0,0 +1,304 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    instruction::{Opcode, Program},
+    trace::TraceEvent,
+    vm::VM,
+};
+
+/// How many of the most executed pcs the report lists
+const HOT_PCS: usize = 10;
+
+/// Collects a `Profile` from a VM as it runs, using its trace handler
+pub struct Profiler {
+    recorder: Rc<RefCell<Recorder>>,
+}
+
+/// Everything counted while profiling
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// Executions of each instruction, by mnemonic
+    pub opcodes: BTreeMap<&'static str, u64>,
+    /// Executions of each pc
+    pub pcs: Vec<u64>,
+    /// Instructions and time spent in each distinct call stack
+    pub stacks: Vec<StackSample>,
+    /// How many times each function was entered
+    pub calls: BTreeMap<String, u64>,
+    program: Program,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackSample {
+    /// Function names, outermost first
+    pub frames: Vec<String>,
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+/// Totals for one function. Self counts only cover the function's own
+/// instructions, while totals include the functions it calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionStats {
+    pub name: String,
+    pub calls: u64,
+    pub self_instructions: u64,
+    pub self_time: Duration,
+    pub total_instructions: u64,
+    pub total_time: Duration,
+}
+
+impl Profiler {
+    /// Starts profiling `vm`, replacing its trace handler
+    pub fn attach(vm: &mut VM) -> Profiler {
+        let program = vm.program();
+        let mut names = HashMap::new();
+        for (name, pc) in program.debug.labels.iter().rev() {
+            names.insert(*pc, name.clone());
+        }
+        let recorder = Rc::new(RefCell::new(Recorder {
+            names,
+            nodes: vec![],
+            children: HashMap::new(),
+            frames: vec![],
+            last: None,
+            profile: Profile {
+                opcodes: BTreeMap::new(),
+                pcs: vec![0; program.code.len()],
+                stacks: vec![],
+                calls: BTreeMap::new(),
+                program: program.clone(),
+            },
+        }));
+
+        let handler = Rc::clone(&recorder);
+        vm.set_trace_handler(move |event| handler.borrow_mut().record(event));
+        Profiler { recorder }
+    }
+
+    /// What has been recorded so far
+    pub fn profile(&self) -> Profile {
+        let recorder = self.recorder.borrow();
+        let mut profile = recorder.profile.clone();
+        profile.stacks = recorder
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.instructions > 0)
+            .map(|(idx, node)| StackSample {
+                frames: recorder.path(idx),
+                instructions: node.instructions,
+                time: node.time,
+            })
+            .collect();
+        profile
+    }
+}
+
+/// One function in one particular call stack
+struct Node {
+    parent: Option<usize>,
+    function: String,
+    instructions: u64,
+    time: Duration,
+}
+
+struct Recorder {
+    names: HashMap<usize, String>,
+    nodes: Vec<Node>,
+    /// Maps a parent node and a called pc to the child node
+    children: HashMap<(Option<usize>, usize), usize>,
+    /// The node of every active call, innermost last
+    frames: Vec<usize>,
+    /// When the previous instruction started, and the node it ran in
+    last: Option<(Instant, usize)>,
+    profile: Profile,
+}
+
+impl Recorder {
+    fn record(&mut self, event: &TraceEvent) {
+        let now = Instant::now();
+        if let Some((start, node)) = self.last {
+            self.nodes[node].time += now - start;
+        }
+
+        if self.frames.is_empty() {
+            let entry = self.profile.program.entry;
+            let node = self.enter(None, entry);
+            self.frames.push(node);
+        }
+        let current = *self.frames.last().unwrap();
+        self.nodes[current].instructions += 1;
+        *self
+            .profile
+            .opcodes
+            .entry(event.opcode.mnemonic())
+            .or_default() += 1;
+        if let Some(count) = self.profile.pcs.get_mut(event.pc) {
+            *count += 1;
+        }
+
+        match event.opcode {
+            Opcode::Call(target) => {
+                let node = self.enter(Some(current), *target);
+                self.frames.push(node);
+            }
+            Opcode::TailCall(target) => {
+                let parent = self.nodes[current].parent;
+                let node = self.enter(parent, *target);
+                *self.frames.last_mut().unwrap() = node;
+            }
+            Opcode::Return | Opcode::ReturnVoid if self.frames.len() > 1 => {
+                self.frames.pop();
+            }
+            _ => {}
+        }
+        self.last = Some((now, current));
+    }
+
+    /// Finds or makes the node for calling `pc` from `parent`, counting the call
+    fn enter(&mut self, parent: Option<usize>, pc: usize) -> usize {
+        let function = match self.names.get(&pc) {
+            Some(name) => name.clone(),
+            None => format!("pc {pc}"),
+        };
+        *self.profile.calls.entry(function.clone()).or_default() += 1;
+
+        *self.children.entry((parent, pc)).or_insert_with(|| {
+            self.nodes.push(Node {
+                parent,
+                function,
+                instructions: 0,
+                time: Duration::ZERO,
+            });
+            self.nodes.len() - 1
+        })
+    }
+
+    fn path(&self, mut node: usize) -> Vec<String> {
+        let mut frames = vec![self.nodes[node].function.clone()];
+        while let Some(parent) = self.nodes[node].parent {
+            frames.push(self.nodes[parent].function.clone());
+            node = parent;
+        }
+        frames.reverse();
+        frames
+    }
+}
+
+impl Profile {
+    /// Per-function totals, hottest (most instructions of its own) first
+    pub fn functions(&self) -> Vec<FunctionStats> {
+        let mut functions: BTreeMap<&str, FunctionStats> = BTreeMap::new();
+        for sample in &self.stacks {
+            for (depth, name) in sample.frames.iter().enumerate() {
+                let stats = functions.entry(name).or_insert_with(|| FunctionStats {
+                    name: name.clone(),
+                    calls: self.calls.get(name).copied().unwrap_or(0),
+                    self_instructions: 0,
+                    self_time: Duration::ZERO,
+                    total_instructions: 0,
+                    total_time: Duration::ZERO,
+                });
+                if depth == sample.frames.len() - 1 {
+                    stats.self_instructions += sample.instructions;
+                    stats.self_time += sample.time;
+                }
+                // recursive calls appear more than once, but only count once
+                if !sample.frames[..depth].contains(name) {
+                    stats.total_instructions += sample.instructions;
+                    stats.total_time += sample.time;
+                }
+            }
+        }
+
+        let mut functions: Vec<FunctionStats> = functions.into_values().collect();
+        functions.sort_by(|a, b| {
+            b.self_instructions
+                .cmp(&a.self_instructions)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        functions
+    }
+
+    /// Every stack as `outer;inner count`, weighted by instructions executed,
+    /// for flamegraph tools such as `inferno` or `flamegraph.pl`
+    pub fn folded_stacks(&self) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|x| format!("{} {}", x.frames.join(";"), x.instructions))
+            .collect();
+        lines.sort();
+        lines.into_iter().map(|x| x + "\n").collect()
+    }
+
+    /// A readable summary of the hottest functions, opcodes and pcs
+    pub fn report(&self) -> String {
+        let total: u64 = self.pcs.iter().sum();
+        let percent = |x: u64| 100.0 * x as f64 / total.max(1) as f64;
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "{:<20} {:>8} {:>12} {:>7} {:>12} {:>12} {:>12}",
+            "function", "calls", "self instrs", "self %", "self time", "total instrs", "total time"
+        )
+        .unwrap();
+        for x in self.functions() {
+            writeln!(
+                out,
+                "{:<20} {:>8} {:>12} {:>6.1}% {:>12} {:>12} {:>12}",
+                x.name,
+                x.calls,
+                x.self_instructions,
+                percent(x.self_instructions),
+                format!("{:.2?}", x.self_time),
+                x.total_instructions,
+                format!("{:.2?}", x.total_time)
+            )
+            .unwrap();
+        }
+
+        let mut opcodes: Vec<_> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        writeln!(out, "\n{:<20} {:>8} {:>7}", "opcode", "count", "%").unwrap();
+        for (name, count) in opcodes {
+            writeln!(out, "{name:<20} {count:>8} {:>6.1}%", percent(*count)).unwrap();
+        }
+
+        let mut pcs: Vec<_> = self.pcs.iter().enumerate().filter(|x| *x.1 > 0).collect();
+        pcs.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(&b.0)));
+        writeln!(out, "\n{:<20} {:>8} {:>7}  instruction", "pc", "count", "%").unwrap();
+        for (pc, count) in pcs.into_iter().take(HOT_PCS) {
+            let location = match self.label_before(pc) {
+                Some((label, start)) => format!("{pc} ({label}+{})", pc - start),
+                None => pc.to_string(),
+            };
+            writeln!(
+                out,
+                "{location:<20} {count:>8} {:>6.1}%  {}",
+                percent(*count),
+                self.program.code[pc]
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    fn label_before(&self, pc: usize) -> Option<(&str, usize)> {
+        self.program
+            .debug
+            .labels
+            .iter()
+            .filter(|(_, x)| *x <= pc)
+            .max_by_key(|(_, x)| *x)
+            .map(|(name, x)| (name.as_str(), *x))
+    }
+}
@@ -0,0 +1,161 @@
+use std::{collections::HashMap, fmt::Write as _, io::Write, time::Instant};
+
+use crate::instruction::{LeiaValue, Opcode, Program};
+
+/// What the VM is about to execute, passed to the trace handler before each instruction
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent<'a> {
+    pub pc: usize,
+    pub opcode: &'a Opcode,
+    /// How many calls deep execution is, 0 in the entry point
+    pub depth: usize,
+    /// The operand stack, topmost value last
+    pub stack: &'a [LeiaValue],
+}
+
+// The sinks below ignore write errors, so a closed pipe never stops the program.
+
+/// Writes one line per instruction, indented by call depth:
+/// `   12  ADD  [Int(1), Int(2)]`
+pub fn human_readable(mut out: impl Write) -> impl FnMut(&TraceEvent) {
+    move |event| {
+        let stack: Vec<String> = event.stack.iter().map(|x| format!("{x:?}")).collect();
+        let _ = writeln!(
+            out,
+            "{:>5}  {}{}  [{}]",
+            event.pc,
+            "  ".repeat(event.depth),
+            event.opcode,
+            stack.join(", ")
+        );
+    }
+}
+
+/// Writes one JSON object per instruction:
+/// `{"pc":12,"op":"ADD","depth":0,"stack":[1,2]}`
+pub fn json_lines(mut out: impl Write) -> impl FnMut(&TraceEvent) {
+    move |event| {
+        let mut line = format!(
+            "{{\"pc\":{},\"op\":\"{}\",",
+            event.pc,
+            event.opcode.mnemonic()
+        );
+        if let Some(operand) = operand(event.opcode) {
+            write!(line, "\"operand\":{operand},").unwrap();
+        }
+        let stack: Vec<String> = event.stack.iter().map(json_value).collect();
+        write!(
+            line,
+            "\"depth\":{},\"stack\":[{}]}}",
+            event.depth,
+            stack.join(",")
+        )
+        .unwrap();
+        let _ = writeln!(out, "{line}");
+    }
+}
+
+/// Writes function enter and exit events in the Chrome trace-event format, which
+/// `chrome://tracing` and Perfetto can open. Functions are named after the labels in
+/// the program's debug info. The closing `]` is left off, which the format allows,
+/// so a trace is still valid when the program stops early.
+pub fn chrome_trace<W: Write>(mut out: W, program: &Program) -> impl FnMut(&TraceEvent) + use<W> {
+    let mut names = HashMap::new();
+    for (name, pc) in program.debug.labels.iter().rev() {
+        names.insert(*pc, name.clone());
+    }
+    let name_of = move |pc: usize| names.get(&pc).cloned().unwrap_or(format!("pc {pc}"));
+    let entry = name_of(program.entry);
+    let start = Instant::now();
+    let mut started = false;
+    // the functions entered so far, innermost last
+    let mut open: Vec<String> = vec![];
+
+    move |event| {
+        let ts = start.elapsed().as_secs_f64() * 1_000_000.0;
+        let mut record = |name: &str, phase: char| {
+            let prefix = if started { "" } else { "[" };
+            started = true;
+            let _ = writeln!(
+                out,
+                "{prefix}{{\"name\":{},\"ph\":\"{phase}\",\"ts\":{ts:.3},\"pid\":1,\"tid\":1}},",
+                json_string(name)
+            );
+        };
+
+        if open.is_empty() {
+            record(&entry, 'B');
+            open.push(entry.clone());
+        }
+        match event.opcode {
+            Opcode::Call(target) => {
+                let name = name_of(*target);
+                record(&name, 'B');
+                open.push(name);
+            }
+            // the called function replaces the current one
+            Opcode::TailCall(target) => {
+                record(&open.pop().unwrap(), 'E');
+                let name = name_of(*target);
+                record(&name, 'B');
+                open.push(name);
+            }
+            Opcode::Return | Opcode::ReturnVoid if open.len() > 1 => {
+                record(&open.pop().unwrap(), 'E');
+            }
+            Opcode::Halt => {
+                while let Some(name) = open.pop() {
+                    record(&name, 'E');
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn operand(opcode: &Opcode) -> Option<String> {
+    match opcode {
+        Opcode::Push(x) => Some(x.0.to_string()),
+        Opcode::Call(x)
+        | Opcode::CallNative(x)
+        | Opcode::TailCall(x)
+        | Opcode::Jump(x)
+        | Opcode::JumpIfZero(x)
+        | Opcode::JumpIfNotZero(x)
+        | Opcode::LoadLocal(x)
+        | Opcode::StoreLocal(x)
+        | Opcode::Increment(x) => Some(x.to_string()),
+        _ => None,
+    }
+}
+
+fn json_value(value: &LeiaValue) -> String {
+    match value {
+        LeiaValue::Int(x) => x.to_string(),
+        #[cfg(feature = "bigint")]
+        LeiaValue::BigInt(x) => x.to_string(),
+        LeiaValue::Float(x) if x.is_finite() => format!("{x:?}"),
+        // JSON has no infinity or NaN
+        LeiaValue::Float(x) => json_string(&x.to_string()),
+        LeiaValue::Str(x) => json_string(x),
+        LeiaValue::Bool(x) => x.to_string(),
+        LeiaValue::Nil => "null".to_string(),
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,446 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+};
+
+use crate::instruction::{Opcode, Program};
+
+/// A problem found in a program without running it
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VerifyError {
+    pub pc: usize,
+    /// The nearest label at or before `pc`, if the program has debug info
+    pub label: Option<String>,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VerifyErrorKind {
+    EntryOutOfBounds(usize),
+    JumpOutOfBounds(usize),
+    ConstantOutOfBounds(u32),
+    /// An instruction needs more values than the stack can hold at that point
+    StackUnderflow {
+        depth: isize,
+        needed: isize,
+    },
+    /// Two paths reach the same instruction with different stack depths
+    InconsistentStackDepth {
+        expected: isize,
+        found: isize,
+    },
+    /// A function returns with different stack effects on different paths
+    InconsistentReturn {
+        expected: isize,
+        found: isize,
+    },
+    ReturnFromEntry,
+    StackNotEmptyAtHalt(isize),
+    UninitializedLocal(usize),
+}
+
+impl Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyErrorKind::EntryOutOfBounds(x) => write!(f, "entry point {x} is out of bounds"),
+            VerifyErrorKind::JumpOutOfBounds(x) => write!(f, "jump target {x} is out of bounds"),
+            VerifyErrorKind::ConstantOutOfBounds(x) => write!(f, "constant {x} does not exist"),
+            VerifyErrorKind::StackUnderflow { depth, needed } => write!(
+                f,
+                "stack underflow: needs {needed} values but the stack holds {depth}"
+            ),
+            VerifyErrorKind::InconsistentStackDepth { expected, found } => write!(
+                f,
+                "stack depth is {found} on one path and {expected} on another"
+            ),
+            VerifyErrorKind::InconsistentReturn { expected, found } => write!(
+                f,
+                "function returns leaving {found} values on one path and {expected} on another"
+            ),
+            VerifyErrorKind::ReturnFromEntry => write!(f, "RET outside of a called function"),
+            VerifyErrorKind::StackNotEmptyAtHalt(x) => {
+                write!(f, "stack holds {x} values at HALT")
+            }
+            VerifyErrorKind::UninitializedLocal(x) => {
+                write!(f, "local {x} is read before any path stores it")
+            }
+        }
+    }
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "pc {} ({}): {}", self.pc, label, self.kind),
+            None => write!(f, "pc {}: {}", self.pc, self.kind),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// A straight-line run of instructions `[start, end)` that is only entered at `start`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Splits the code into basic blocks, in pc order
+pub fn basic_blocks(program: &Program) -> Vec<BasicBlock> {
+    let len = program.code.len();
+    let mut leaders = BTreeSet::from([0, program.entry]);
+    for (pc, opcode) in program.code.iter().enumerate() {
+        if let Some(target) = opcode.jump_target() {
+            leaders.insert(target);
+        }
+        if ends_block(opcode) {
+            leaders.insert(pc + 1);
+        }
+    }
+
+    let leaders: Vec<usize> = leaders.into_iter().filter(|x| *x < len).collect();
+    leaders
+        .iter()
+        .enumerate()
+        .map(|(idx, start)| BasicBlock {
+            start: *start,
+            end: leaders.get(idx + 1).copied().unwrap_or(len),
+        })
+        .collect()
+}
+
+fn ends_block(opcode: &Opcode) -> bool {
+    opcode.jump_target().is_some()
+        || matches!(opcode, Opcode::Return | Opcode::ReturnVoid | Opcode::Halt)
+}
+
+/// Checks a program before it runs, returning every problem found.
+///
+/// Stack depths are tracked per function (the entry point and every call target)
+/// relative to the depth when the function was entered. A plain label may pop its
+/// caller's values, which is how its arguments are passed, but the entry function and
+/// `.fn` functions must keep their depth from going negative. Each call applies the
+/// callee's net stack effect.
+/// A native's arity isn't known until the host registers it, so after `CALL_NATIVE` the
+/// stack depth on that path is unknown and only local slots are checked.
+pub fn verify(program: &Program) -> Vec<VerifyError> {
+    let mut errors = Vec::new();
+    let len = program.code.len();
+
+    if program.entry > len {
+        errors.push(VerifyErrorKind::EntryOutOfBounds(program.entry).at(program, program.entry));
+    }
+
+    for (pc, opcode) in program.code.iter().enumerate() {
+        // jumping just past the last instruction ends the program
+        if let Some(target) = opcode.jump_target()
+            && target > len
+        {
+            errors.push(VerifyErrorKind::JumpOutOfBounds(target).at(program, pc));
+        }
+        if let Opcode::Push(x) = opcode
+            && x.0 as usize >= program.constants.len()
+        {
+            errors.push(VerifyErrorKind::ConstantOutOfBounds(x.0).at(program, pc));
+        }
+    }
+
+    // control flow can't be followed safely through bad targets
+    if !errors.is_empty() {
+        return errors;
+    }
+
+    let mut functions: BTreeSet<usize> = program
+        .code
+        .iter()
+        .filter_map(|x| match x {
+            Opcode::Call(target) | Opcode::TailCall(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+    functions.insert(program.entry);
+
+    // Paths are followed a basic block at a time, and states are only kept at block starts.
+    let blocks = basic_blocks(program);
+
+    // Work out what each function does to the stack, repeating until recursive
+    // functions settle. The pass count is capped in case a recursion never does.
+    let mut summaries = HashMap::new();
+    for _ in 0..=functions.len() {
+        let previous = summaries.clone();
+        for function in &functions {
+            let summary = analyze(program, &blocks, *function, &previous, None);
+            summaries.insert(*function, summary);
+        }
+        if summaries == previous {
+            break;
+        }
+    }
+
+    for function in &functions {
+        analyze(program, &blocks, *function, &summaries, Some(&mut errors));
+    }
+
+    errors.sort();
+    errors.dedup();
+    errors
+}
+
+impl VerifyErrorKind {
+    fn at(self, program: &Program, pc: usize) -> VerifyError {
+        let label = program
+            .debug
+            .labels
+            .iter()
+            .filter(|(_, x)| *x <= pc)
+            .max_by_key(|(_, x)| *x)
+            .map(|(name, _)| name.clone());
+        VerifyError {
+            pc,
+            label,
+            kind: self,
+        }
+    }
+}
+
+/// What calling a function does to its caller's stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Summary {
+    /// Lowest depth reached, relative to the depth at the call
+    min: isize,
+    /// Depth when returning, relative to the depth at the call. `None` if it never returns
+    ret: Option<isize>,
+}
+
+/// The abstract state at the start of an instruction
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    depth: Option<isize>,    // `None` once a native call has made it unknown
+    stored: BTreeSet<usize>, // local slots stored on at least one path
+}
+
+/// How many values an instruction pops and pushes
+fn stack_effect(opcode: &Opcode) -> (isize, isize) {
+    match opcode {
+        Opcode::Push(_) | Opcode::LoadLocal(_) => (0, 1),
+        Opcode::Pop | Opcode::StoreLocal(_) | Opcode::Print => (1, 0),
+        Opcode::JumpIfZero(_)
+        | Opcode::JumpIfNotZero(_)
+        | Opcode::ToInt
+        | Opcode::ToFloat
+        | Opcode::ToStr
+        | Opcode::Length
+        | Opcode::Upper
+        | Opcode::Lower
+        | Opcode::Trim => (1, 1),
+        Opcode::Substring => (3, 1),
+        Opcode::Split => (2, 2),
+        Opcode::Equals
+        | Opcode::NotEqual
+        | Opcode::GreaterThan
+        | Opcode::GreaterThanEqual
+        | Opcode::LessThan
+        | Opcode::LessThanEqual
+        | Opcode::Add
+        | Opcode::Subtract
+        | Opcode::Multiply
+        | Opcode::Divide
+        | Opcode::Modulo
+        | Opcode::IndexOf => (2, 1),
+        Opcode::Call(_)
+        | Opcode::CallNative(_)
+        | Opcode::TailCall(_)
+        | Opcode::Return
+        | Opcode::ReturnVoid
+        | Opcode::Jump(_)
+        | Opcode::Increment(_)
+        | Opcode::Halt => (0, 0),
+    }
+}
+
+/// Walks every path through one function block by block, summarizing its stack effect.
+/// Problems are only recorded when `errors` is given, since summaries of the
+/// functions it calls may still be incomplete otherwise.
+fn analyze(
+    program: &Program,
+    blocks: &[BasicBlock],
+    function: usize,
+    summaries: &HashMap<usize, Summary>,
+    mut errors: Option<&mut Vec<VerifyError>>,
+) -> Summary {
+    let is_entry = function == program.entry;
+    // a declared function's arguments are moved off the caller's stack into its locals,
+    // and it starts with a stack of its own
+    let declared = match program.functions.iter().find(|x| x.pc == function) {
+        Some(declared) if !is_entry => Some(declared.arity as isize),
+        _ => None,
+    };
+    let owns_stack = is_entry || declared.is_some();
+    let mut report = |kind: VerifyErrorKind, pc: usize| {
+        if let Some(errors) = errors.as_mut() {
+            errors.push(kind.at(program, pc));
+        }
+    };
+
+    let arity = declared.unwrap_or(0);
+    let mut summary = Summary {
+        min: -arity,
+        ret: None,
+    };
+    let mut states: HashMap<usize, State> = HashMap::new();
+    let mut worklist = vec![function];
+    states.insert(
+        function,
+        State {
+            depth: Some(0),
+            stored: (0..arity as usize).collect(),
+        },
+    );
+
+    while let Some(start) = worklist.pop() {
+        // anything but a block start is the end of the program, which just finishes it
+        let Ok(idx) = blocks.binary_search_by_key(&start, |x| x.start) else {
+            continue;
+        };
+        let block = blocks[idx];
+        let mut state = states[&start].clone();
+        for pc in block.start..block.end {
+            let opcode = &program.code[pc];
+
+            let (pops, pushes) = stack_effect(opcode);
+            if let Some(depth) = state.depth.as_mut() {
+                if owns_stack && *depth < pops {
+                    report(
+                        VerifyErrorKind::StackUnderflow {
+                            depth: *depth,
+                            needed: pops,
+                        },
+                        pc,
+                    );
+                    // carry on as if the values were there, so one mistake is reported once
+                    *depth = pops;
+                }
+                summary.min = summary.min.min(*depth - pops - arity);
+                *depth += pushes - pops;
+            }
+            // the stack depth (relative to the caller's) if this instruction returns
+            let mut returned = None;
+
+            let successors = match opcode {
+                Opcode::LoadLocal(x) | Opcode::Increment(x) => {
+                    if !state.stored.contains(x) {
+                        report(VerifyErrorKind::UninitializedLocal(*x), pc);
+                    }
+                    vec![pc + 1]
+                }
+                Opcode::StoreLocal(x) => {
+                    state.stored.insert(*x);
+                    vec![pc + 1]
+                }
+                Opcode::Jump(target) => vec![*target],
+                Opcode::JumpIfZero(target) | Opcode::JumpIfNotZero(target) => vec![*target, pc + 1],
+                Opcode::Call(target) | Opcode::TailCall(target) => match summaries.get(target) {
+                    Some(Summary {
+                        min,
+                        ret: Some(ret),
+                    }) => {
+                        if let Some(depth) = state.depth.as_mut() {
+                            if owns_stack && *depth + min < 0 {
+                                report(
+                                    VerifyErrorKind::StackUnderflow {
+                                        depth: *depth,
+                                        needed: -min,
+                                    },
+                                    pc,
+                                );
+                            }
+                            summary.min = summary.min.min(*depth + min - arity);
+                            *depth += ret;
+                        }
+                        if let Opcode::Call(_) = opcode {
+                            vec![pc + 1]
+                        } else {
+                            // `TAIL_CALL` returns like `CALL` followed by `RET`
+                            returned = match declared {
+                                Some(arity) => Some(1 - arity),
+                                None => state.depth,
+                            };
+                            vec![]
+                        }
+                    }
+                    // the callee never returns (or isn't understood yet), so nothing follows
+                    _ => vec![],
+                },
+                // a native's arity is only known once the host registers it
+                Opcode::CallNative(_) => {
+                    state.depth = None;
+                    vec![pc + 1]
+                }
+                Opcode::Return | Opcode::ReturnVoid => {
+                    // a declared function leaves exactly its return value in place of its
+                    // arguments, whatever its own stack holds
+                    returned = match declared {
+                        Some(arity) if *opcode == Opcode::Return => Some(1 - arity),
+                        Some(arity) => Some(-arity),
+                        None => state.depth,
+                    };
+                    vec![]
+                }
+                Opcode::Halt => {
+                    if is_entry
+                        && let Some(depth) = state.depth
+                        && depth != 0
+                    {
+                        report(VerifyErrorKind::StackNotEmptyAtHalt(depth), pc);
+                    }
+                    vec![]
+                }
+                _ => vec![pc + 1],
+            };
+
+            if let Some(found) = returned {
+                if is_entry {
+                    report(VerifyErrorKind::ReturnFromEntry, pc);
+                }
+                match summary.ret {
+                    Some(expected) if expected != found => {
+                        report(VerifyErrorKind::InconsistentReturn { expected, found }, pc)
+                    }
+                    Some(_) => {}
+                    None => summary.ret = Some(found),
+                }
+            }
+
+            // only the last instruction of a block can go anywhere but the next one
+            if pc + 1 < block.end {
+                continue;
+            }
+            for next in successors {
+                match states.get_mut(&next) {
+                    None => {
+                        states.insert(next, state.clone());
+                        worklist.push(next);
+                    }
+                    Some(existing) => {
+                        if let (Some(expected), Some(found)) = (existing.depth, state.depth)
+                            && expected != found
+                        {
+                            report(
+                                VerifyErrorKind::InconsistentStackDepth { expected, found },
+                                next,
+                            );
+                        }
+                        let before = existing.stored.len();
+                        existing.stored.extend(state.stored.iter().copied());
+                        if existing.stored.len() != before {
+                            worklist.push(next);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    summary
+}
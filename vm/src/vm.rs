@@ -1,6 +1,54 @@
-use crate::instruction::{ConstantValue, LeiaValue, Opcode, Program};
+use std::fmt::Display;
+
+use crate::{
+    instruction::{ConstantValue, LeiaValue, MAX_LOCALS, Opcode, Program},
+    trace::TraceEvent,
+};
 
 type OutputHandler = Box<dyn FnMut(&LeiaValue)>;
+type FuelCost = Box<dyn Fn(&Opcode, &[LeiaValue]) -> u64>;
+type TraceHandler = Box<dyn FnMut(&TraceEvent)>;
+type NativeFunction = Box<dyn FnMut(&[LeiaValue]) -> Result<LeiaValue, String>>;
+
+/// How many values from the top of the operand stack are kept in a `VmError`
+const STACK_SNAPSHOT_LEN: usize = 8;
+
+/// How many of the innermost call frames a `StackOverflow` names
+const FRAME_SNAPSHOT_LEN: usize = 5;
+
+/// Limits on how much memory a running program can use, and how it does arithmetic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    /// Most values the operand stack can hold
+    pub max_stack: usize,
+    /// Most calls that can be active at once, not counting the entry point
+    pub max_call_depth: usize,
+    pub arithmetic: ArithmeticMode,
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            max_stack: 1 << 20,
+            max_call_depth: 100_000,
+            arithmetic: ArithmeticMode::Checked,
+        }
+    }
+}
+
+/// What happens when Int arithmetic overflows. The same program behaves the same way
+/// in debug and release builds in either mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    /// Overflow is an `IntegerOverflow` runtime error.
+    ///
+    /// With the `bigint` feature, overflowing results become BigInts instead and this
+    /// mode never reports overflow. Cargo unifies features, so any crate in the build
+    /// that enables `bigint` changes this for every user of the VM.
+    Checked,
+    /// Results wrap around in two's complement, so `i64::MAX + 1` is `i64::MIN`
+    Wrapping,
+}
 
 pub struct VM {
     pc: usize,
@@ -8,232 +56,781 @@ pub struct VM {
     stack: Vec<LeiaValue>,
     call_stack: Vec<StackFrame>,
     output_handler: Option<OutputHandler>,
+    fuel_cost: Option<FuelCost>,
+    /// Registered host functions, indexed like `Program::natives`
+    natives: Vec<Option<Native>>,
+    /// Index into `Program::functions` of the function starting at each pc
+    function_at: Vec<Option<usize>>,
+    /// How many local slots a frame for the function starting at each pc gets
+    frame_size: Vec<usize>,
+    trace_handler: Option<TraceHandler>,
+    config: VmConfig,
 }
 
 #[derive(Debug, Clone)]
 pub struct StackFrame {
     return_address: usize,
-    locals: Vec<LeiaValue>,
+    /// The pc the frame's function starts at
+    function: usize,
+    locals: Vec<Option<LeiaValue>>,
+    /// The lowest operand stack index the function may pop
+    stack_base: usize,
+    returns: Returns,
+}
+
+/// What returning from a frame does to the operand stack
+#[derive(Debug, Clone, PartialEq)]
+enum Returns {
+    /// A plain label shares its caller's stack and leaves it as it is
+    Shared,
+    /// A `.fn` function leaves one value for `RET` and none for `RET_VOID`
+    AsInstructed,
+    /// A `.fn` function that tail called a plain label leaves one value either way,
+    /// like the `RET` after a `CALL` would have
+    OneValue,
+    /// A `.fn` function that tail called another `.fn` function leaves the callee's
+    /// value for `RET`, and for `RET_VOID` the value its `RET` would have found
+    /// under the call
+    OneValueOr(LeiaValue),
+}
+
+impl StackFrame {
+    /// The pc of the `CALL` that made this frame; execution resumes just after it
+    pub fn return_address(&self) -> usize {
+        self.return_address
+    }
+
+    /// The pc the frame's function starts at
+    pub fn function(&self) -> usize {
+        self.function
+    }
+
+    /// The lowest operand stack index the frame may pop. A `.fn` function's values
+    /// start here, while a plain label shares its caller's base.
+    pub fn stack_base(&self) -> usize {
+        self.stack_base
+    }
+
+    /// Every local slot of the frame, `None` where nothing has been stored yet
+    pub fn locals(&self) -> &[Option<LeiaValue>] {
+        &self.locals
+    }
+}
+
+/// How a call to `VM::run` ended without error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// Execution reached `HALT` or ran past the last instruction
+    Finished,
+    /// The fuel given to `VM::run_with_fuel` ran out before the program finished.
+    /// The pc points at the next instruction, so running again carries on from there.
+    OutOfFuel,
+    /// `VM::run_for` executed all the instructions it was asked to
+    Paused,
+}
+
+/// Whether the program can keep going after `VM::step`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Running,
+    /// Execution reached `HALT` or ran past the last instruction
+    Finished,
+}
+
+/// A fault raised by the running program
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    pub pc: usize,
+    pub opcode: Opcode,
+    /// The top of the operand stack before the faulting instruction ran, topmost value last
+    pub stack_top: Vec<LeiaValue>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmErrorKind {
+    StackUnderflow,
+    CallStackUnderflow,
+    StackNotEmpty(usize),
+    ConstantOutOfBounds(u32),
+    UninitializedLocal {
+        index: usize,
+        function: String,
+    },
+    LocalOutOfRange(usize),
+    DivisionByZero,
+    IntegerOverflow(&'static str),
+    TypeMismatch {
+        operation: &'static str,
+        left: &'static str,
+        right: &'static str,
+    },
+    InvalidOperand {
+        operation: &'static str,
+        value: &'static str,
+    },
+    InvalidConversion {
+        value: LeiaValue,
+        to: &'static str,
+    },
+    SubstringOutOfBounds {
+        start: LeiaValue,
+        end: LeiaValue,
+        /// Length of the string in chars
+        len: usize,
+    },
+    EmptySeparator,
+    StackOverflow {
+        limit: StackLimit,
+        /// Names of the innermost functions running, innermost first
+        frames: Vec<String>,
+    },
+    UnknownNative(String),
+    MissingArguments {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    Native {
+        name: String,
+        message: String,
+    },
+}
+
+/// Which limit in `VmConfig` a program ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackLimit {
+    Operand(usize),
+    CallDepth(usize),
+}
+
+impl Display for VmErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            VmErrorKind::CallStackUnderflow => write!(f, "call stack underflow"),
+            VmErrorKind::MissingArguments {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "function `{function}` takes {expected} arguments but the stack only holds {found}"
+            ),
+            VmErrorKind::UnknownNative(name) => {
+                write!(f, "native function `{name}` is not registered")
+            }
+            VmErrorKind::Native { name, message } => {
+                write!(f, "native function `{name}` failed: {message}")
+            }
+            VmErrorKind::StackNotEmpty(len) => {
+                write!(f, "stack is not empty at halt ({len} values left)")
+            }
+            VmErrorKind::ConstantOutOfBounds(idx) => write!(f, "constant {idx} does not exist"),
+            VmErrorKind::UninitializedLocal { index, function } => {
+                write!(
+                    f,
+                    "read of uninitialized local {index} in function `{function}`"
+                )
+            }
+            VmErrorKind::DivisionByZero => write!(f, "division by zero"),
+            VmErrorKind::IntegerOverflow(operation) => {
+                write!(f, "integer overflow in {operation}")
+            }
+            VmErrorKind::TypeMismatch {
+                operation,
+                left,
+                right,
+            } => write!(f, "invalid types for {operation}: {left} and {right}"),
+            VmErrorKind::InvalidOperand { operation, value } => {
+                write!(f, "invalid type for {operation}: {value}")
+            }
+            VmErrorKind::InvalidConversion { value, to } => {
+                write!(f, "cannot convert {value:?} to {to}")
+            }
+            VmErrorKind::SubstringOutOfBounds { start, end, len } => write!(
+                f,
+                "substring {start}..{end} is out of bounds for a string of {len} chars"
+            ),
+            VmErrorKind::EmptySeparator => write!(f, "cannot split on an empty separator"),
+            VmErrorKind::LocalOutOfRange(x) => write!(
+                f,
+                "local {x} is out of range, a frame holds at most {MAX_LOCALS} locals"
+            ),
+            VmErrorKind::StackOverflow { limit, frames } => {
+                match limit {
+                    StackLimit::Operand(x) => write!(
+                        f,
+                        "stack overflow: the operand stack holds at most {x} values"
+                    )?,
+                    StackLimit::CallDepth(x) => {
+                        write!(f, "stack overflow: calls can be at most {x} deep")?
+                    }
+                }
+                write!(f, " (in {})", frames.join(", called from "))
+            }
+        }
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stack: Vec<String> = self.stack_top.iter().map(|x| format!("{x:?}")).collect();
+        writeln!(
+            f,
+            "runtime error at pc {} ({}): {}",
+            self.pc, self.opcode, self.kind
+        )?;
+        write!(f, "  stack top: [{}]", stack.join(", "))
+    }
+}
+
+impl std::error::Error for VmError {}
+
+struct Native {
+    arity: usize,
+    function: NativeFunction,
+}
+
+/// What the VM should do with the pc once an instruction has executed
+enum Flow {
+    Next,
+    Jumped,
+    Halt,
 }
 
 impl VM {
     pub fn new(program: Program) -> VM {
+        VM::with_config(program, VmConfig::default())
+    }
+
+    pub fn with_config(program: Program, config: VmConfig) -> VM {
+        let natives = program.natives.iter().map(|_| None).collect();
+        let mut function_at = vec![None; program.code.len() + 1];
+        let mut frame_size = vec![0; program.code.len() + 1];
+        for (idx, function) in program.functions.iter().enumerate() {
+            if let Some(slot) = function_at.get_mut(function.pc) {
+                *slot = Some(idx);
+                // programs built in code can skip the assembler's checks
+                frame_size[function.pc] = function.locals.min(MAX_LOCALS);
+            }
+        }
+        // undeclared functions get a slot for every local they touch
+        let targets = program.code.iter().filter_map(|x| match x {
+            Opcode::Call(target) | Opcode::TailCall(target) => Some(*target),
+            _ => None,
+        });
+        for start in targets.chain([program.entry]) {
+            if start < frame_size.len() && function_at[start].is_none() && frame_size[start] == 0 {
+                frame_size[start] = local_count(&program, start);
+            }
+        }
+
         VM {
             pc: program.entry,
-            program,
-            stack: vec![],
             call_stack: vec![StackFrame {
-                locals: vec![],
                 return_address: 0,
+                function: program.entry,
+                locals: vec![None; frame_size.get(program.entry).copied().unwrap_or(0)],
+                stack_base: 0,
+                returns: Returns::Shared,
             }],
+            program,
+            stack: vec![],
             output_handler: None,
+            fuel_cost: None,
+            natives,
+            function_at,
+            frame_size,
+            trace_handler: None,
+            config,
         }
     }
 
-    fn locals(&self) -> &Vec<LeiaValue> {
+    /// The pc of the next instruction to execute
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// The operand stack, topmost value last
+    pub fn stack(&self) -> &[LeiaValue] {
+        &self.stack
+    }
+
+    /// The local slots of the innermost call frame
+    pub fn locals(&self) -> &[Option<LeiaValue>] {
         &self.call_stack.last().unwrap().locals
     }
 
-    fn locals_mut(&mut self) -> &mut Vec<LeiaValue> {
+    /// Every call frame, starting with the entry point's and ending with the innermost
+    pub fn frames(&self) -> &[StackFrame] {
+        &self.call_stack
+    }
+
+    fn locals_mut(&mut self) -> &mut Vec<Option<LeiaValue>> {
         &mut self.call_stack.last_mut().unwrap().locals
     }
 
-    pub fn run(&mut self) -> () {
-        //println!("PC START: {}", self.pc);
-        loop {
-            //println!("{:?}", self.stack);
-            if self.pc >= self.program.code.len() {
+    /// Makes the local slots for a call to `fn_address`. Functions declared with `.fn`
+    /// take their arguments straight off the stack, and the stack index their own values
+    /// start at is returned with the slots. Undeclared ones pop them with `STORE_LOCAL`.
+    fn arguments(
+        &mut self,
+        fn_address: usize,
+    ) -> Result<(Vec<Option<LeiaValue>>, Option<usize>), VmErrorKind> {
+        let mut locals = vec![None; self.frame_size.get(fn_address).copied().unwrap_or(0)];
+        let declared = self.function_at.get(fn_address).copied().flatten();
+        let Some(function) = declared.map(|x| &self.program.functions[x]) else {
+            return Ok((locals, None));
+        };
+
+        let len = self.stack.len();
+        if self.available() < function.arity {
+            return Err(VmErrorKind::MissingArguments {
+                function: function.name.clone(),
+                expected: function.arity,
+                found: self.available(),
+            });
+        }
+        let base = len - function.arity;
+        for (slot, value) in locals.iter_mut().zip(self.stack.drain(base..)) {
+            *slot = Some(value);
+        }
+        Ok((locals, Some(base)))
+    }
+
+    /// The local in slot `idx` of the innermost frame, if it has been stored
+    fn local_mut(&mut self, idx: usize) -> Result<&mut LeiaValue, VmErrorKind> {
+        let frame = self.call_stack.last_mut().unwrap();
+        match frame.locals.get_mut(idx) {
+            Some(Some(value)) => Ok(value),
+            _ => Err(VmErrorKind::UninitializedLocal {
+                index: idx,
+                function: function_name(&self.program, &self.function_at, frame.function),
+            }),
+        }
+    }
+
+    /// How many values the innermost frame can pop
+    fn available(&self) -> usize {
+        self.stack.len() - self.call_stack.last().unwrap().stack_base
+    }
+
+    fn push(&mut self, value: LeiaValue) -> Result<(), VmErrorKind> {
+        if self.stack.len() >= self.config.max_stack {
+            return Err(self.overflow(StackLimit::Operand(self.config.max_stack)));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn overflow(&self, limit: StackLimit) -> VmErrorKind {
+        let frames = self.call_stack.iter().rev().take(FRAME_SNAPSHOT_LEN);
+        VmErrorKind::StackOverflow {
+            limit,
+            frames: frames
+                .map(|x| function_name(&self.program, &self.function_at, x.function))
+                .collect(),
+        }
+    }
+
+    fn pop(&mut self) -> Result<LeiaValue, VmErrorKind> {
+        if self.available() == 0 {
+            return Err(VmErrorKind::StackUnderflow);
+        }
+        Ok(self.stack.pop().unwrap())
+    }
+
+    fn peek(&self) -> Result<&LeiaValue, VmErrorKind> {
+        if self.available() == 0 {
+            return Err(VmErrorKind::StackUnderflow);
+        }
+        Ok(self.stack.last().unwrap())
+    }
+
+    /// Replaces the top two values with the result of `op`.
+    /// The stack is left untouched if the operation fails.
+    fn binary_op(
+        &mut self,
+        op: fn(&LeiaValue, &LeiaValue) -> Result<LeiaValue, VmErrorKind>,
+    ) -> Result<(), VmErrorKind> {
+        let len = self.stack.len();
+        if self.available() < 2 {
+            return Err(VmErrorKind::StackUnderflow);
+        }
+        let result = op(&self.stack[len - 2], &self.stack[len - 1])?;
+        self.stack.truncate(len - 2);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// `binary_op` with whichever of the two operations the arithmetic mode calls for
+    fn arithmetic_op(
+        &mut self,
+        checked: fn(&LeiaValue, &LeiaValue) -> Result<LeiaValue, VmErrorKind>,
+        wrapping: fn(&LeiaValue, &LeiaValue) -> Result<LeiaValue, VmErrorKind>,
+    ) -> Result<(), VmErrorKind> {
+        match self.config.arithmetic {
+            ArithmeticMode::Checked => self.binary_op(checked),
+            ArithmeticMode::Wrapping => self.binary_op(wrapping),
+        }
+    }
+
+    /// Replaces the top value with the result of `op`, leaving it if the operation fails
+    fn unary_op(
+        &mut self,
+        op: fn(&LeiaValue) -> Result<LeiaValue, VmErrorKind>,
+    ) -> Result<(), VmErrorKind> {
+        let result = op(self.peek()?)?;
+        *self.stack.last_mut().unwrap() = result;
+        Ok(())
+    }
+
+    fn error(&self, kind: VmErrorKind, opcode: Opcode) -> VmError {
+        let start = self.stack.len().saturating_sub(STACK_SNAPSHOT_LEN);
+        VmError {
+            kind,
+            pc: self.pc,
+            opcode,
+            stack_top: self.stack[start..].to_vec(),
+        }
+    }
+
+    pub fn run(&mut self) -> Result<RunOutcome, VmError> {
+        while self.step()? == StepOutcome::Running {}
+        Ok(RunOutcome::Finished)
+    }
+
+    /// Executes at most `count` instructions. A paused VM carries on where it
+    /// left off the next time it runs.
+    pub fn run_for(&mut self, count: usize) -> Result<RunOutcome, VmError> {
+        for _ in 0..count {
+            if self.step()? == StepOutcome::Finished {
+                return Ok(RunOutcome::Finished);
+            }
+        }
+        if self.pc < self.program.code.len() {
+            Ok(RunOutcome::Paused)
+        } else {
+            Ok(RunOutcome::Finished)
+        }
+    }
+
+    /// Runs until the program finishes or the fuel runs out.
+    /// Every instruction costs 1 fuel unless `set_fuel_cost` says otherwise, and an
+    /// instruction only runs if the fuel left covers its whole cost.
+    pub fn run_with_fuel(&mut self, fuel: u64) -> Result<RunOutcome, VmError> {
+        let mut fuel = fuel;
+        while let Some(code) = self.program.code.get(self.pc) {
+            let cost = match &self.fuel_cost {
+                Some(cost) => cost(code, &self.stack),
+                None => 1,
+            };
+            if cost > fuel {
+                return Ok(RunOutcome::OutOfFuel);
+            }
+            fuel -= cost;
+
+            if self.step()? == StepOutcome::Finished {
                 break;
             }
+        }
+        Ok(RunOutcome::Finished)
+    }
 
-            // Temp hack: clone the opcode for now
-            // Having some borrow checker issues having it as an immutable ref to self
-            // and then using a mutable self ref later.
-            // TODO: maybe write a separate function which passes the touched properties as args to avoid this?
-            let code = self.program.code[self.pc].clone();
-            /*
-            println!(
-                "{}: code {:?} stack: {:?} locals: {:?}",
-                self.pc,
-                code,
-                self.stack,
-                self.locals()
-            );
-            */
-
-            match code {
-                Opcode::Pop => {
-                    self.stack
-                        .pop()
-                        .expect("Attempted to pop a value where none exists!");
-                }
-                Opcode::Push(constant_index) => {
-                    //println!("{:?}", self.program.constants);
-                    //println!("push const: {:?}", constant_index);
-                    let constant = &self.program.constants[constant_index.0 as usize];
-                    self.stack.push(match constant {
-                        ConstantValue::Int(x) => LeiaValue::Int(*x),
-                        ConstantValue::Float(x) => LeiaValue::Float(*x),
-                        ConstantValue::Str(x) => LeiaValue::Str(x.clone()),
-                    });
-                }
-                Opcode::Jump(addr) => {
-                    self.pc = addr;
-                    continue; // don't increment pc
-                }
-                Opcode::Increment(idx) => {
-                    if let Some(local) = self.locals_mut().get_mut(idx) {
-                        match local {
-                            LeiaValue::Int(n) => *n += 1,
-                            _ => panic!("Cannot increment non-int local"),
+    /// Executes the instruction at the pc.
+    /// Stepping a finished program does nothing, or runs its `HALT` again.
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+        let Some(code) = self.program.code.get(self.pc) else {
+            return Ok(StepOutcome::Finished);
+        };
+        // Temp hack: clone the opcode for now
+        // Having some borrow checker issues having it as an immutable ref to self
+        // and then using a mutable self ref later.
+        let code = code.clone();
+
+        if let Some(handler) = self.trace_handler.as_mut() {
+            handler(&TraceEvent {
+                pc: self.pc,
+                opcode: &code,
+                depth: self.call_stack.len() - 1,
+                stack: &self.stack,
+            });
+        }
+
+        match self.execute(&code) {
+            Ok(Flow::Next) => self.pc += 1,
+            Ok(Flow::Jumped) => {}
+            Ok(Flow::Halt) => return Ok(StepOutcome::Finished),
+            Err(kind) => return Err(self.error(kind, code)),
+        }
+        if self.pc < self.program.code.len() {
+            Ok(StepOutcome::Running)
+        } else {
+            Ok(StepOutcome::Finished)
+        }
+    }
+
+    fn execute(&mut self, code: &Opcode) -> Result<Flow, VmErrorKind> {
+        match *code {
+            Opcode::Pop => {
+                self.pop()?;
+            }
+            Opcode::Push(ref constant_index) => {
+                let constant = self
+                    .program
+                    .constants
+                    .get(constant_index.0 as usize)
+                    .ok_or(VmErrorKind::ConstantOutOfBounds(constant_index.0))?;
+                let value = match constant {
+                    ConstantValue::Int(x) => LeiaValue::Int(*x),
+                    #[cfg(feature = "bigint")]
+                    ConstantValue::BigInt(x) => LeiaValue::from_big(x.clone()),
+                    ConstantValue::Float(x) => LeiaValue::Float(*x),
+                    ConstantValue::Str(x) => LeiaValue::Str(x.clone()),
+                    ConstantValue::Bool(x) => LeiaValue::Bool(*x),
+                    ConstantValue::Nil => LeiaValue::Nil,
+                };
+                self.push(value)?;
+            }
+            Opcode::Jump(addr) => {
+                self.pc = addr;
+                return Ok(Flow::Jumped);
+            }
+            Opcode::Increment(idx) => {
+                let mode = self.config.arithmetic;
+                let local = self.local_mut(idx)?;
+                *local = match local {
+                    LeiaValue::Int(n) => match (n.checked_add(1), mode) {
+                        (Some(n), _) => LeiaValue::Int(n),
+                        (None, ArithmeticMode::Wrapping) => LeiaValue::Int(n.wrapping_add(1)),
+                        #[cfg(feature = "bigint")]
+                        (None, ArithmeticMode::Checked) => {
+                            LeiaValue::from_big(num_bigint::BigInt::from(*n) + 1u32)
                         }
-                    } else {
-                        panic!("Local variable index out of bounds: {}", idx);
+                        #[cfg(not(feature = "bigint"))]
+                        (None, ArithmeticMode::Checked) => {
+                            return Err(VmErrorKind::IntegerOverflow("increment"));
+                        }
+                    },
+                    #[cfg(feature = "bigint")]
+                    LeiaValue::BigInt(n) => LeiaValue::from_big(&*n + 1u32),
+                    _ => {
+                        return Err(VmErrorKind::InvalidOperand {
+                            operation: "increment",
+                            value: local.type_name(),
+                        });
                     }
+                };
+            }
+            Opcode::Add => self.arithmetic_op(
+                |a, b| a.concat(b).or_else(|_| a.add(b)),
+                |a, b| a.concat(b).or_else(|_| a.wrapping_add(b)),
+            )?,
+            Opcode::Subtract => self.arithmetic_op(LeiaValue::sub, LeiaValue::wrapping_sub)?,
+            Opcode::Multiply => self.arithmetic_op(LeiaValue::mul, LeiaValue::wrapping_mul)?,
+            Opcode::Divide => self.arithmetic_op(LeiaValue::div, LeiaValue::wrapping_div)?,
+            Opcode::Modulo => self.arithmetic_op(LeiaValue::modulo, LeiaValue::wrapping_modulo)?,
+            Opcode::ToInt => self.unary_op(LeiaValue::to_int)?,
+            Opcode::ToFloat => self.unary_op(LeiaValue::to_float)?,
+            Opcode::ToStr => self.unary_op(|x| Ok(x.to_str()))?,
+            Opcode::Length => self.unary_op(LeiaValue::length)?,
+            Opcode::Substring => {
+                let len = self.stack.len();
+                if self.available() < 3 {
+                    return Err(VmErrorKind::StackUnderflow);
                 }
-                Opcode::Add => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.add(b));
+                let result =
+                    self.stack[len - 3].substring(&self.stack[len - 2], &self.stack[len - 1])?;
+                self.stack.truncate(len - 3);
+                self.stack.push(result);
+            }
+            Opcode::IndexOf => self.binary_op(LeiaValue::index_of)?,
+            Opcode::Upper => self.unary_op(LeiaValue::upper)?,
+            Opcode::Lower => self.unary_op(LeiaValue::lower)?,
+            Opcode::Trim => self.unary_op(LeiaValue::trim)?,
+            Opcode::Split => {
+                let len = self.stack.len();
+                if self.available() < 2 {
+                    return Err(VmErrorKind::StackUnderflow);
                 }
-                Opcode::Subtract => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.sub(b));
+                let (before, after) = self.stack[len - 2].split(&self.stack[len - 1])?;
+                self.stack[len - 2] = before;
+                self.stack[len - 1] = after;
+            }
+            Opcode::Print => {
+                let val = self.pop()?;
+                if let Some(handler) = self.output_handler.as_mut() {
+                    handler(&val);
+                } else {
+                    println!("{}", val);
                 }
-                Opcode::Multiply => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.mul(b));
+            }
+            Opcode::Halt => {
+                if !self.stack.is_empty() {
+                    return Err(VmErrorKind::StackNotEmpty(self.stack.len()));
                 }
-                Opcode::Divide => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.div(b));
+                return Ok(Flow::Halt);
+            }
+            Opcode::JumpIfZero(addr) => {
+                // we don't pop the value off the stack here when comparing.
+                // This is to support logical operators where we don't want to pop the condition
+                // See "jumping back and forth" chapter in Crafting interpreters
+                if !self.peek()?.is_truthy() {
+                    self.pc = addr;
+                    return Ok(Flow::Jumped);
                 }
-                Opcode::Modulo => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.modulo(b));
+            }
+            Opcode::JumpIfNotZero(addr) => {
+                if self.peek()?.is_truthy() {
+                    self.pc = addr;
+                    return Ok(Flow::Jumped);
                 }
-                Opcode::Print => {
-                    let val = self.stack.pop().unwrap();
-                    if let Some(handler) = self.output_handler.as_mut() {
-                        handler(&val);
-                    } else {
-                        println!("{}", val);
-                    }
+            }
+            Opcode::LoadLocal(idx) => {
+                let val = self.local_mut(idx)?.clone();
+                self.push(val)?;
+            }
+            Opcode::StoreLocal(idx) => {
+                let val = self.pop()?;
+                let locals = self.locals_mut();
+                // frames are sized up front, so this only grows for code reached
+                // in ways `local_count` can't see
+                if idx >= MAX_LOCALS {
+                    return Err(VmErrorKind::LocalOutOfRange(idx));
                 }
-                Opcode::Halt => {
-                    if (self.stack.len()) > 0 {
-                        panic!("Stack length is not zero!: {}", self.stack.len());
-                    }
-                    break;
+                if idx >= locals.len() {
+                    locals.resize(idx + 1, None);
                 }
-                Opcode::JumpIfZero(addr) => {
-                    // we don't pop the value off the stack here when comparing.
-                    // This is to support logical operators where we don't want to pop the condition
-                    // See "jumping back and forth" chapter in Crafting interpreters
-                    let val = self.stack.last().unwrap(); // .pop().unwrap();
-                    //println!("JUMP? {:?}", val);
-                    match val {
-                        LeiaValue::Int(x) => {
-                            if *x == 0 {
-                                self.pc = addr; // - 1; 
-                                continue; // don't increment PC
-                            }
-                        }
-                        _ => panic!("Invalid jp if zero value!"),
-                    }
+                locals[idx] = Some(val);
+            }
+            Opcode::Equals => self.binary_op(LeiaValue::eq)?,
+            Opcode::NotEqual => self.binary_op(LeiaValue::neq)?,
+            Opcode::GreaterThan => self.binary_op(LeiaValue::gt)?,
+            Opcode::GreaterThanEqual => self.binary_op(LeiaValue::gte)?,
+            Opcode::LessThan => self.binary_op(LeiaValue::lt)?,
+            Opcode::LessThanEqual => self.binary_op(LeiaValue::lte)?,
+            Opcode::Call(fn_address) => {
+                if self.call_stack.len() > self.config.max_call_depth {
+                    return Err(self.overflow(StackLimit::CallDepth(self.config.max_call_depth)));
                 }
-                Opcode::JumpIfNotZero(addr) => {
-                    let val = self.stack.last().unwrap(); //.pop().unwrap();
-                    //println!("JUMP? {:?}", val);
-                    match val {
-                        LeiaValue::Int(x) => {
-                            if *x != 0 {
-                                self.pc = addr;
-                                continue; // don't increment PC
-                            }
-                        }
-                        _ => panic!("Invalid jp if zero value!"),
+                let (locals, base) = self.arguments(fn_address)?;
+                let frame = StackFrame {
+                    return_address: self.pc,
+                    function: fn_address,
+                    locals,
+                    stack_base: base.unwrap_or(self.call_stack.last().unwrap().stack_base),
+                    returns: match base {
+                        Some(_) => Returns::AsInstructed,
+                        None => Returns::Shared,
+                    },
+                };
+
+                self.call_stack.push(frame);
+
+                self.pc = fn_address;
+                return Ok(Flow::Jumped);
+            }
+            Opcode::TailCall(fn_address) => {
+                let (locals, base) = self.arguments(fn_address)?;
+                // reuse the current frame, so it returns to the same place and in
+                // the same way as `CALL` followed by `RET`
+                let frame = self.call_stack.last_mut().unwrap();
+                match (&frame.returns, base) {
+                    (Returns::Shared, Some(base)) => {
+                        frame.stack_base = base;
+                        frame.returns = Returns::AsInstructed;
                     }
-                }
-                Opcode::LoadLocal(idx) => {
-                    let val = self
-                        .locals()
-                        .get(idx)
-                        .expect("Local variable index out of bounds")
-                        .clone();
-                    self.stack.push(val);
-                }
-                Opcode::StoreLocal(idx) => {
-                    let val = self
-                        .stack
-                        .pop()
-                        .expect(format!("Stack underflow on StoreLocal index {}", idx).as_str());
-
-                    if idx == self.locals_mut().len() {
-                        // Append the new local since it's exactly the next index
-                        self.locals_mut().push(val);
-                    } else if idx < self.locals_mut().len() {
-                        // Overwrite existing local
-                        self.locals_mut()[idx] = val;
-                    } else {
-                        panic!("Local variable index out of bounds: {}", idx);
+                    (Returns::Shared, None) => {}
+                    (_, Some(_)) => {
+                        // only the top of what the current function left under the
+                        // arguments can still be returned, if the callee returns nothing
+                        let under = if self.stack.len() > frame.stack_base {
+                            self.stack.pop().unwrap()
+                        } else {
+                            LeiaValue::Nil
+                        };
+                        self.stack.truncate(frame.stack_base);
+                        frame.returns = Returns::OneValueOr(under);
                     }
+                    (_, None) => frame.returns = Returns::OneValue,
                 }
-                Opcode::Equals => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.eq(b));
-                }
-                Opcode::NotEqual => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.neq(b));
-                }
-                Opcode::GreaterThan => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.gt(b));
-                }
-                Opcode::GreaterThanEqual => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.gte(b));
-                }
-                Opcode::LessThan => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.lt(b));
+                frame.function = fn_address;
+                frame.locals = locals;
+
+                self.pc = fn_address;
+                return Ok(Flow::Jumped);
+            }
+            Opcode::CallNative(idx) => {
+                let name = self.program.natives.get(idx);
+                let Some(native) = self.natives.get_mut(idx).and_then(Option::as_mut) else {
+                    let name = name.cloned().unwrap_or(format!("#{idx}"));
+                    return Err(VmErrorKind::UnknownNative(name));
+                };
+                let len = self.stack.len();
+                if len - self.call_stack.last().unwrap().stack_base < native.arity {
+                    return Err(VmErrorKind::StackUnderflow);
                 }
-                Opcode::LessThanEqual => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a.lte(b));
+                // arguments stay on the stack until the call succeeds, like `binary_op`
+                let result =
+                    (native.function)(&self.stack[len - native.arity..]).map_err(|message| {
+                        VmErrorKind::Native {
+                            name: name.unwrap().clone(),
+                            message,
+                        }
+                    })?;
+                self.stack.truncate(len - native.arity);
+                self.push(result)?;
+            }
+            Opcode::Return | Opcode::ReturnVoid => {
+                // the bottom frame belongs to the entry point and can't be returned from
+                if self.call_stack.len() < 2 {
+                    return Err(VmErrorKind::CallStackUnderflow);
                 }
-                Opcode::Call(fn_address) => {
-                    // probably need to push a new stack frame
-                    let frame = StackFrame {
-                        return_address: self.pc,
-                        locals: vec![],
+                // pop last frame off the stack
+                let frame = self.call_stack.pop().unwrap();
+                // a `.fn` function hands back exactly one value (nil if it has none),
+                // or nothing for `RET_VOID`, and whatever else it left is dropped.
+                // Plain labels share their caller's stack, so it's left alone.
+                if frame.returns != Returns::Shared {
+                    let value = if self.stack.len() > frame.stack_base {
+                        self.stack.pop().unwrap()
+                    } else {
+                        LeiaValue::Nil
                     };
-
-                    self.call_stack.push(frame);
-
-                    self.pc = fn_address;
-                    continue; // don't increment PC
-                }
-                Opcode::Return => {
-                    // pop last frame off the stack
-                    let frame = self.call_stack.pop().expect("Call stack underflow");
-                    // and jump to its return address
-                    self.pc = frame.return_address;
+                    self.stack.truncate(frame.stack_base);
+                    match frame.returns {
+                        _ if *code == Opcode::Return => self.push(value)?,
+                        Returns::OneValue => self.push(value)?,
+                        Returns::OneValueOr(under) => self.push(under)?,
+                        _ => {}
+                    }
                 }
+                // and jump to its return address
+                self.pc = frame.return_address;
             }
+        }
 
-            self.pc += 1;
+        Ok(Flow::Next)
+    }
+
+    /// Makes `function` callable as `CALL_NATIVE name`. It gets `arity` arguments,
+    /// first argument first, and its result is pushed in their place.
+    /// An `Err` stops the program with a runtime error carrying the message.
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: FnMut(&[LeiaValue]) -> Result<LeiaValue, String> + 'static,
+    {
+        // natives the program never calls can't be reached, so there's nothing to keep
+        if let Some(idx) = self.program.natives.iter().position(|x| x == name) {
+            self.natives[idx] = Some(Native {
+                arity,
+                function: Box::new(function),
+            });
         }
     }
 
@@ -249,7 +846,74 @@ impl VM {
         self.output_handler = Some(Box::new(handler));
     }
 
-    pub fn clear_output_handler(&mut self) -> () {
+    pub fn clear_output_handler(&mut self) {
         self.output_handler = None
     }
+
+    /// Sets how much fuel each instruction costs in `run_with_fuel`.
+    /// The cost function sees the instruction and the operand stack before it runs,
+    /// so an `ADD` of two strings can be charged more than one of two numbers.
+    pub fn set_fuel_cost<F>(&mut self, cost: F)
+    where
+        F: Fn(&Opcode, &[LeiaValue]) -> u64 + 'static,
+    {
+        self.fuel_cost = Some(Box::new(cost));
+    }
+
+    pub fn clear_fuel_cost(&mut self) {
+        self.fuel_cost = None
+    }
+
+    /// Calls `handler` before every instruction executes.
+    /// See `trace` for handlers that write traces in common formats.
+    pub fn set_trace_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&TraceEvent) + 'static,
+    {
+        self.trace_handler = Some(Box::new(handler));
+    }
+
+    pub fn clear_trace_handler(&mut self) {
+        self.trace_handler = None
+    }
+}
+
+/// The name of the function starting at `pc`, for error messages
+fn function_name(program: &Program, function_at: &[Option<usize>], pc: usize) -> String {
+    if let Some(idx) = function_at.get(pc).copied().flatten() {
+        return program.functions[idx].name.clone();
+    }
+    match program.debug.label_at(pc) {
+        Some(name) => name.to_string(),
+        None => format!("<pc {pc}>"),
+    }
+}
+
+/// One more than the highest local index used by the code reachable from `start`
+/// without following calls
+fn local_count(program: &Program, start: usize) -> usize {
+    let mut seen = vec![false; program.code.len()];
+    let mut pending = vec![start];
+    let mut count = 0;
+    while let Some(pc) = pending.pop() {
+        let Some(opcode) = program.code.get(pc) else {
+            continue;
+        };
+        if std::mem::replace(&mut seen[pc], true) {
+            continue;
+        }
+        match *opcode {
+            Opcode::LoadLocal(idx) | Opcode::StoreLocal(idx) | Opcode::Increment(idx) => {
+                count = count.max(idx.saturating_add(1).min(MAX_LOCALS));
+                pending.push(pc + 1);
+            }
+            Opcode::Jump(target) => pending.push(target),
+            Opcode::JumpIfZero(target) | Opcode::JumpIfNotZero(target) => {
+                pending.extend([target, pc + 1]);
+            }
+            Opcode::TailCall(_) | Opcode::Return | Opcode::ReturnVoid | Opcode::Halt => {}
+            _ => pending.push(pc + 1),
+        }
+    }
+    count
 }
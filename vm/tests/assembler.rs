@@ -0,0 +1,275 @@
+use vm::{
+    assembler::{AssembleErrorKind, quote_string, try_parse_assembly},
+    instruction::{ConstantValue, LeiaValue},
+};
+
+#[test]
+fn reports_every_error() {
+    let asm = "
+.const 0 1
+.const 1
+
+.main
+    PUSH_CONST 0
+    FOO
+    STORE_LOCAL x
+    JUMP nowhere
+    LOAD_LOCAL
+    HALT
+";
+    let errors = try_parse_assembly(asm).unwrap_err();
+    let kinds: Vec<_> = errors.iter().map(|e| (e.line, e.column, &e.kind)).collect();
+
+    assert_eq!(
+        vec![
+            (3, 1, &AssembleErrorKind::MalformedConst),
+            (
+                7,
+                5,
+                &AssembleErrorKind::UnknownInstruction("FOO".to_string())
+            ),
+            (
+                8,
+                17,
+                &AssembleErrorKind::InvalidOperand {
+                    instruction: "STORE_LOCAL".to_string(),
+                    operand: "x".to_string()
+                }
+            ),
+            (
+                9,
+                10,
+                &AssembleErrorKind::UnknownLabel("nowhere".to_string())
+            ),
+            (
+                10,
+                5,
+                &AssembleErrorKind::MissingOperand {
+                    instruction: "LOAD_LOCAL".to_string()
+                }
+            ),
+        ],
+        kinds
+    );
+}
+
+#[test]
+fn error_display_points_at_token() {
+    let errors = try_parse_assembly(".main\n    JUMP nowhere ; go").unwrap_err();
+    assert_eq!(1, errors.len());
+    assert_eq!("    JUMP nowhere ; go", errors[0].snippet);
+    assert_eq!(
+        "error: unknown label `nowhere`\n --> line 2, column 10\n2 |     JUMP nowhere ; go\n  |          ^",
+        errors[0].to_string()
+    );
+}
+
+#[test]
+fn valid_program_assembles() {
+    let program = try_parse_assembly(".const 0 42\n.main\n    PUSH_CONST 0\n    PRINT\n    HALT")
+        .expect("program should assemble");
+    assert_eq!(3, program.code.len());
+    assert_eq!(1, program.constants.len());
+}
+
+#[test]
+fn constants_use_declared_index() {
+    let program =
+        try_parse_assembly(".const 1 \"b\"\n.const 0 7\n.main\n    PUSH_CONST 1\n    HALT")
+            .expect("program should assemble");
+    assert_eq!(
+        vec![ConstantValue::Int(7), ConstantValue::Str("b".to_string())],
+        program.constants
+    );
+}
+
+#[test]
+fn constant_index_errors() {
+    let asm =
+        ".const 0 1\n.const 0 2\n.const 5 3\n.const x 4\n.main\n    PUSH_CONST 3\n    PUSH_CONST 5";
+    let errors = try_parse_assembly(asm).unwrap_err();
+    let kinds: Vec<_> = errors.iter().map(|e| (e.line, &e.kind)).collect();
+
+    assert_eq!(
+        vec![
+            (2, &AssembleErrorKind::DuplicateConstIndex(0)),
+            (4, &AssembleErrorKind::InvalidConstIndex("x".to_string())),
+            (6, &AssembleErrorKind::UndefinedConstant(3)),
+        ],
+        kinds
+    );
+}
+
+#[test]
+fn constants_can_leave_gaps() {
+    let program = try_parse_assembly(".const 5 42\n.main\n    PUSH_CONST 5\n    PRINT\n    HALT")
+        .expect("program should assemble");
+    let mut expected = vec![ConstantValue::Nil; 5];
+    expected.push(ConstantValue::Int(42));
+    assert_eq!(expected, program.constants);
+
+    let errors = try_parse_assembly(".const 5 42\n.main\n    PUSH_CONST 2\n    HALT").unwrap_err();
+    assert_eq!(
+        vec![(3, &AssembleErrorKind::UndefinedConstant(2))],
+        errors.iter().map(|e| (e.line, &e.kind)).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn huge_constant_indices_are_out_of_range() {
+    let errors = try_parse_assembly(".const 4000000000 1\n.main\n    HALT").unwrap_err();
+    assert_eq!(
+        vec![AssembleErrorKind::ConstIndexOutOfRange(4_000_000_000)],
+        errors.into_iter().map(|e| e.kind).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        "constant index 4000000000 is out of range, indices go up to 1048575",
+        AssembleErrorKind::ConstIndexOutOfRange(4_000_000_000).to_string()
+    );
+}
+
+#[test]
+fn local_slots_are_bounded() {
+    let errors = try_parse_assembly(
+        ".main
+    LOAD_LOCAL 100000000000
+    LOAD_LOCAL 18446744073709551615
+    STORE_LOCAL 5000000000
+    INC 65536
+    LOAD_LOCAL 65535
+    HALT
+.fn f 0 100000000000
+    RET",
+    )
+    .unwrap_err();
+    let kinds: Vec<_> = errors.iter().map(|e| (e.line, &e.kind)).collect();
+    assert_eq!(
+        vec![
+            (2, &AssembleErrorKind::LocalOutOfRange(100000000000)),
+            (3, &AssembleErrorKind::LocalOutOfRange(usize::MAX)),
+            (4, &AssembleErrorKind::LocalOutOfRange(5000000000)),
+            (5, &AssembleErrorKind::LocalOutOfRange(65536)),
+            (
+                8,
+                &AssembleErrorKind::TooManyLocals {
+                    function: "f".to_string(),
+                    locals: 100000000000
+                }
+            ),
+        ],
+        kinds
+    );
+}
+
+#[test]
+fn string_constants() {
+    let asm = r####".const 0 "a;b" ; comment with "quotes"
+.const 1 "tab\tquote\"nl\n\u{1F600}"
+.const 2 r#"raw "\n";"#
+.const 3 "two
+lines"
+.const 4 "  spaced  "
+.main
+    HALT"####;
+    let program = try_parse_assembly(asm).expect("program should assemble");
+    let strings: Vec<_> = program
+        .constants
+        .iter()
+        .map(|x| match x {
+            ConstantValue::Str(s) => s.as_str(),
+            _ => panic!("expected a string constant"),
+        })
+        .collect();
+
+    assert_eq!(
+        vec![
+            "a;b",
+            "tab\tquote\"nl\n\u{1F600}",
+            "raw \"\\n\";",
+            "two\nlines",
+            "  spaced  "
+        ],
+        strings
+    );
+}
+
+#[test]
+fn string_errors() {
+    let errors = try_parse_assembly(".const 0 \"bad \\q\"\n.const 1 \"open\n.main").unwrap_err();
+    let kinds: Vec<_> = errors.iter().map(|e| (e.line, e.column, &e.kind)).collect();
+    assert_eq!(
+        vec![
+            (1, 15, &AssembleErrorKind::InvalidEscape("\\q".to_string())),
+            (2, 10, &AssembleErrorKind::UnterminatedString),
+        ],
+        kinds
+    );
+}
+
+#[test]
+fn quoted_strings_round_trip() {
+    let value = "semi;colon \"quoted\" back\\slash\ttab\nnewline \u{7} bell привет";
+    let asm = format!(".const 0 {}\n.main\n    HALT", quote_string(value));
+    let program = try_parse_assembly(&asm).expect("program should assemble");
+    assert_eq!(
+        vec![ConstantValue::Str(value.to_string())],
+        program.constants
+    );
+}
+
+#[test]
+fn numeric_literals() {
+    let program = try_parse_assembly(
+        ".const 0 0xff\n.const 1 -0b1010\n.const 2 0o17\n.const 3 1_000_000\n.const 4 6.02e23\n.const 5 -inf\n.const 6 3f64\n.const 7 0x1fi64\n.const 8 .5\n.main\n HALT",
+    )
+    .unwrap();
+    assert_eq!(
+        vec![
+            ConstantValue::Int(255),
+            ConstantValue::Int(-10),
+            ConstantValue::Int(15),
+            ConstantValue::Int(1_000_000),
+            ConstantValue::Float(6.02e23),
+            ConstantValue::Float(f64::NEG_INFINITY),
+            ConstantValue::Float(3.0),
+            ConstantValue::Int(31),
+            ConstantValue::Float(0.5),
+        ],
+        program.constants
+    );
+
+    let errors = try_parse_assembly(".const 0 1__0\n.const 1 0x\n.main\n HALT").unwrap_err();
+    let kinds: Vec<_> = errors.into_iter().map(|e| e.kind).collect();
+    assert_eq!(
+        vec![
+            AssembleErrorKind::InvalidConstValue("1__0".to_string()),
+            AssembleErrorKind::InvalidConstValue("0x".to_string()),
+        ],
+        kinds
+    );
+}
+
+#[cfg(not(feature = "bigint"))]
+#[test]
+fn ints_past_64_bits_are_out_of_range() {
+    let errors = try_parse_assembly(".const 0 99999999999999999999\n.main\n HALT").unwrap_err();
+    assert_eq!(
+        AssembleErrorKind::IntOutOfRange("99999999999999999999".to_string()),
+        errors[0].kind
+    );
+}
+
+#[test]
+fn floats_print_round_trippably() {
+    for x in [0.1, 1.0, 1e21, 1.0 / 3.0, -2.5e-8] {
+        let printed = LeiaValue::Float(x).to_string();
+        let program = try_parse_assembly(&format!(".const 0 {printed}\n.main\n HALT")).unwrap();
+        assert_eq!(
+            vec![ConstantValue::Float(x)],
+            program.constants,
+            "{printed}"
+        );
+    }
+    assert_eq!("1.0", LeiaValue::Float(1.0).to_string());
+    assert_eq!("NaN", LeiaValue::Float(f64::NAN).to_string());
+}
@@ -0,0 +1,146 @@
+#![cfg(feature = "bigint")]
+
+mod common;
+
+use common::{run_output, run_program};
+use vm::{
+    assembler::parse_assembly,
+    bytecode::BytecodeError,
+    disassembler::disassemble,
+    instruction::{ConstantValue, LeiaValue, Program},
+};
+
+#[test]
+fn overflow_promotes_to_big_ints() {
+    // 30! with a loop, then back down into the Int range
+    let asm = "
+.const 0 1
+.const 1 30
+.const 2 265252859812191058636308480000000
+.main
+    PUSH_CONST 0
+    STORE_LOCAL 0
+    PUSH_CONST 0
+    STORE_LOCAL 1
+.loop
+    LOAD_LOCAL 1
+    PUSH_CONST 1
+    GT
+    JUMPNZ done
+    POP
+    LOAD_LOCAL 0
+    LOAD_LOCAL 1
+    MUL
+    STORE_LOCAL 0
+    INC 1
+    JUMP loop
+.done
+    POP
+    LOAD_LOCAL 0
+    PRINT
+    LOAD_LOCAL 0
+    PUSH_CONST 2
+    EQ
+    PRINT
+    LOAD_LOCAL 0
+    PUSH_CONST 2
+    DIV
+    PRINT
+    HALT";
+    assert_eq!(
+        vec!["265252859812191058636308480000000", "true", "1"],
+        run_output(asm)
+    );
+}
+
+#[test]
+fn big_ints_mix_with_ints_and_floats() {
+    let asm = "
+.const 0 9223372036854775807
+.const 1 1
+.const 2 -0x1_0000_0000_0000_0000
+.const 3 0.5
+.main
+    PUSH_CONST 0
+    PUSH_CONST 1
+    ADD
+    PRINT
+    PUSH_CONST 2
+    PRINT
+    PUSH_CONST 2
+    PUSH_CONST 3
+    MUL
+    PRINT
+    PUSH_CONST 2
+    PUSH_CONST 0
+    LT
+    PRINT
+    PUSH_CONST 2
+    TO_FLOAT
+    TO_INT
+    PRINT
+    HALT";
+    assert_eq!(
+        vec![
+            "9223372036854775808",
+            "-18446744073709551616",
+            "-9.223372036854776e18",
+            "true",
+            "-18446744073709551616",
+        ],
+        run_output(asm)
+    );
+}
+
+#[test]
+fn results_that_fit_are_ints() {
+    let big = LeiaValue::from_big("100000000000000000000".parse().unwrap());
+    assert!(matches!(big, LeiaValue::BigInt(_)));
+    assert_eq!(Ok(LeiaValue::Int(0)), big.sub(&big));
+    assert_eq!(
+        Ok(LeiaValue::Int(10_000_000_000)),
+        big.div(&LeiaValue::Int(10_000_000_000))
+    );
+    assert_eq!(
+        Ok(LeiaValue::Bool(false)),
+        big.eq(&LeiaValue::Int(i64::MAX))
+    );
+}
+
+#[test]
+fn big_constants_survive_bytecode_and_disassembly() {
+    let program = parse_assembly(".const 0 -340282366920938463463374607431768211456\n.main\n HALT");
+    assert!(matches!(program.constants[0], ConstantValue::BigInt(_)));
+    let bytes = program.to_bytes();
+    // the header marks files that need this feature to load
+    assert_eq!(1, bytes[6]);
+    assert_eq!(0, parse_assembly(".main\n HALT").to_bytes()[6]);
+    assert_eq!(program, Program::from_bytes(&bytes).unwrap());
+    assert_eq!(
+        program.constants,
+        parse_assembly(&disassemble(&program)).constants
+    );
+}
+
+#[test]
+fn big_constants_that_fit_are_ints() {
+    // hand-built programs can hold a BigInt constant that should have been an Int
+    let mut program = parse_assembly(
+        ".const 0 0\n.main\n    PUSH_CONST 0\n    JUMPNZ truthy\n    PRINT\n    HALT\n.truthy\n    POP\n    HALT",
+    );
+    program.constants[0] = ConstantValue::BigInt(num_bigint::BigInt::from(0));
+    assert_eq!(vec!["0"], run_program(program.clone()));
+    assert!(matches!(
+        Program::from_bytes(&program.to_bytes()),
+        Err(BytecodeError::UnnormalizedBigInt { .. })
+    ));
+}
+
+#[test]
+fn big_ints_past_the_float_range_convert_to_infinity() {
+    let asm = format!(
+        ".const 0 1{zeros}\n.const 1 -1{zeros}\n.main\n    PUSH_CONST 0\n    TO_FLOAT\n    PRINT\n    PUSH_CONST 1\n    TO_FLOAT\n    PRINT\n    HALT",
+        zeros = "0".repeat(400)
+    );
+    assert_eq!(vec!["inf", "-inf"], run_output(&asm));
+}
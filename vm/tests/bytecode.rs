@@ -0,0 +1,152 @@
+mod common;
+
+use vm::{
+    assembler::{parse_assembly, try_parse_assembly},
+    bytecode::{BytecodeError, FORMAT_VERSION},
+    instruction::Program,
+};
+
+#[test]
+fn asm_programs_round_trip() {
+    for (file, program) in common::example_programs() {
+        let decoded = Program::from_bytes(&program.to_bytes()).expect(&file);
+        assert_eq!(program, decoded, "{file} did not round trip");
+    }
+}
+
+#[test]
+fn all_constant_kinds_round_trip() {
+    let program = parse_assembly(
+        ".const 0 -7\n.const 1 2.5\n.const 2 \"héllo\\n\"\n.const 3 true\n.const 4 nil\n.main\n    HALT",
+    );
+    assert_eq!(program, Program::from_bytes(&program.to_bytes()).unwrap());
+}
+
+#[test]
+fn rejects_every_other_version() {
+    let bytes = parse_assembly(".main\n    HALT").to_bytes();
+    for version in [0, 1, FORMAT_VERSION - 1, FORMAT_VERSION + 1, u16::MAX] {
+        let mut other = bytes.clone();
+        other[4..6].copy_from_slice(&version.to_le_bytes());
+        assert_eq!(
+            Err(BytecodeError::UnsupportedVersion(version)),
+            Program::from_bytes(&other)
+        );
+    }
+}
+
+#[test]
+fn rejects_unknown_feature_flags() {
+    let mut bytes = parse_assembly(".main\n    HALT").to_bytes();
+    bytes[6] = 0x80;
+    assert_eq!(
+        Err(BytecodeError::InvalidTag {
+            what: "feature flags",
+            tag: 0x80,
+            offset: 6
+        }),
+        Program::from_bytes(&bytes)
+    );
+}
+
+#[cfg(not(feature = "bigint"))]
+#[test]
+fn big_integer_files_need_the_bigint_feature() {
+    // the flag a build with `bigint` sets when the constants include big integers
+    let mut bytes = parse_assembly(".main\n    HALT").to_bytes();
+    bytes[6] = 1;
+    let err = Program::from_bytes(&bytes).unwrap_err();
+    assert_eq!(BytecodeError::NeedsFeature("bigint"), err);
+    assert_eq!(
+        "program needs the `bigint` feature, which this build of the VM doesn't have",
+        err.to_string()
+    );
+}
+
+#[test]
+fn rejects_bad_files() {
+    let bytes =
+        parse_assembly(".const 0 1\n.main\n    PUSH_CONST 0\n    PRINT\n    HALT").to_bytes();
+
+    assert_eq!(Err(BytecodeError::BadMagic), Program::from_bytes(b"nope"));
+
+    for len in 6..bytes.len() {
+        assert!(
+            matches!(
+                Program::from_bytes(&bytes[..len]),
+                Err(BytecodeError::Truncated { .. })
+            ),
+            "truncating to {len} bytes should fail"
+        );
+    }
+
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(
+        Err(BytecodeError::TrailingBytes {
+            offset: bytes.len()
+        }),
+        Program::from_bytes(&trailing)
+    );
+}
+
+#[test]
+fn assembled_programs_always_encode() {
+    // the largest operands the assembler accepts
+    let asm = ".const 1048575 1\n.main\n    PUSH_CONST 1048575\n    STORE_LOCAL 65535\n    HALT\n.fn f 65536 65536\n    RET";
+    let program = parse_assembly(asm);
+    assert_eq!(
+        Ok(program.clone()),
+        Program::from_bytes(&program.to_bytes())
+    );
+
+    for line in [
+        "PUSH_CONST 4294967296",
+        "LOAD_LOCAL 4294967296",
+        "STORE_LOCAL 4294967296",
+        "INC 4294967296",
+    ] {
+        assert!(try_parse_assembly(&format!(".main\n    {line}\n    HALT")).is_err());
+    }
+    assert!(try_parse_assembly(".main\n    HALT\n.fn f 0 4294967296\n    RET").is_err());
+    assert!(try_parse_assembly(".const 4294967295 1\n.main\n    HALT").is_err());
+}
+
+#[test]
+fn rejects_out_of_range_locals() {
+    let mut program = parse_assembly(".main\n    LOAD_LOCAL 0\n    HALT\n.fn f 0 1\n    RET");
+    program.code[0] = vm::instruction::Opcode::LoadLocal(100_000);
+    assert!(matches!(
+        Program::from_bytes(&program.to_bytes()),
+        Err(BytecodeError::OutOfRange {
+            what: "local index",
+            value: 100_000,
+            ..
+        })
+    ));
+
+    program.code[0] = vm::instruction::Opcode::LoadLocal(0);
+    program.functions[0].locals = 100_000;
+    assert!(matches!(
+        Program::from_bytes(&program.to_bytes()),
+        Err(BytecodeError::OutOfRange {
+            what: "function locals",
+            value: 100_000,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn rejects_out_of_range_operands() {
+    let mut program = parse_assembly(".main\n    JUMP main");
+    program.code[0] = vm::instruction::Opcode::Jump(5);
+    assert!(matches!(
+        Program::from_bytes(&program.to_bytes()),
+        Err(BytecodeError::OutOfRange {
+            what: "jump target",
+            value: 5,
+            ..
+        })
+    ));
+}
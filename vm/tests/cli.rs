@@ -0,0 +1,60 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn leia(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_leia"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start leia");
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn runs_files_and_stdin() {
+    let output = leia(&["run", "../asm/factorial.s"], b"");
+    assert!(output.status.success());
+    assert_eq!("362880\n", String::from_utf8_lossy(&output.stdout));
+
+    let output = leia(
+        &["run", "--time"],
+        b".const 0 \"hi\"\n.main\n    PUSH_CONST 0\n    PRINT\n    HALT",
+    );
+    assert!(output.status.success());
+    assert_eq!("hi\n", String::from_utf8_lossy(&output.stdout));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("elapsed"));
+}
+
+#[test]
+fn bytecode_round_trip() {
+    let asm = std::fs::read("../asm/fib.s").unwrap();
+    let bytecode = leia(&["asm", "-", "-o", "-"], &asm);
+    assert!(bytecode.status.success());
+
+    let run_source = leia(&["run", "../asm/fib.s"], b"");
+    let run_bytecode = leia(&["run"], &bytecode.stdout);
+    assert!(run_bytecode.status.success());
+    assert_eq!(run_source.stdout, run_bytecode.stdout);
+
+    let disasm = leia(&["disasm"], &bytecode.stdout);
+    assert!(String::from_utf8_lossy(&disasm.stdout).contains(".fn_main"));
+}
+
+#[test]
+fn errors_exit_non_zero() {
+    let output = leia(&["run"], b".main\n    FOO");
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown instruction"));
+
+    let output = leia(&["run"], b".main\n    POP");
+    assert_eq!(Some(1), output.status.code());
+
+    let output = leia(&["check"], b".main\n    LOAD_LOCAL 0\n    HALT");
+    assert_eq!(Some(1), output.status.code());
+
+    let output = leia(&["frobnicate"], b"");
+    assert_eq!(Some(2), output.status.code());
+}
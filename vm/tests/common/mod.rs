@@ -0,0 +1,62 @@
+// Each test crate includes this module and uses only some of it
+#![allow(dead_code)]
+
+use std::{cell::RefCell, rc::Rc};
+
+use vm::{
+    assembler::parse_assembly,
+    instruction::Program,
+    vm::{RunOutcome, VM, VmError},
+};
+
+/// Runs the program to completion, panicking on a runtime error, and returns what it printed
+pub fn run_program(program: Program) -> Vec<String> {
+    let (output, result) = try_run_program(program);
+    result.expect("Program failed at runtime");
+    output
+}
+
+/// Runs the program until it finishes or fails, returning what it printed and how it ended
+pub fn try_run_program(program: Program) -> (Vec<String>, Result<RunOutcome, VmError>) {
+    let mut vm = VM::new(program);
+    let output = capture_output(&mut vm);
+    let result = vm.run();
+    vm.clear_output_handler();
+
+    let output = Rc::try_unwrap(output)
+        .expect("Multiple references to output exist")
+        .into_inner();
+    (output, result)
+}
+
+/// Collects everything the VM prints from now on, for tests that drive the VM themselves
+pub fn capture_output(vm: &mut VM) -> Rc<RefCell<Vec<String>>> {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let output_clone = Rc::clone(&output);
+    vm.set_output_handler(move |val| output_clone.borrow_mut().push(format!("{}", val)));
+    output
+}
+
+/// `run_program` on the assembled source
+pub fn run_output(asm: &str) -> Vec<String> {
+    run_program(parse_assembly(asm))
+}
+
+/// Every example program in `asm/`, assembled, with its file name, in name order
+pub fn example_programs() -> Vec<(String, Program)> {
+    let mut files: Vec<_> = std::fs::read_dir("../asm")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|x| x == "s"))
+        .collect();
+    assert!(!files.is_empty(), "no example programs found in asm/");
+    files.sort();
+    files
+        .into_iter()
+        .map(|path| {
+            let asm = std::fs::read_to_string(&path).unwrap();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, parse_assembly(&asm))
+        })
+        .collect()
+}
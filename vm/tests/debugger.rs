@@ -0,0 +1,41 @@
+mod common;
+
+use vm::{assembler::parse_assembly, debugger::Debugger, vm::VM};
+
+fn debug(commands: &str) -> (String, Vec<String>) {
+    let asm = std::fs::read_to_string("../asm/factorial.s").unwrap();
+    let mut vm = VM::new(parse_assembly(&asm));
+    let printed = common::capture_output(&mut vm);
+
+    let mut out = Vec::new();
+    Debugger::new(vm)
+        .run(commands.as_bytes(), &mut out)
+        .unwrap();
+    let printed = printed.borrow().clone();
+    (String::from_utf8(out).unwrap(), printed)
+}
+
+#[test]
+fn breakpoints_and_frames() {
+    let (out, printed) = debug("break base_case\ncontinue\nbt\ncontinue\n");
+
+    assert!(out.contains("breakpoint at pc 18"));
+    assert!(out.contains("->   18  PUSH_CONST 1 ; 1"));
+    assert!(out.contains("  #0 main\n  #1 factorial, returns to pc 2, 1 locals\n"));
+    assert!(out.contains("  #8 factorial, returns to pc 15, 1 locals\n"));
+    assert!(out.contains("program finished"));
+    assert_eq!(vec!["362880"], printed);
+}
+
+#[test]
+fn step_over_and_out() {
+    // stepping over the outer call runs the whole recursion
+    let (out, _) = debug("step\nnext\n");
+    assert!(out.ends_with(" ->    2  PRINT\n       3  POP\n       4  HALT\n      .factorial\n       5  STORE_LOCAL 0\nstack: [Int(0), Int(362880)]\nlocals: []\n(leia) "));
+
+    // stepping into it and back out lands on the same instruction
+    let (out, printed) = debug("step\nstep\nstep\nfinish\nquit\nstep\n");
+    assert!(out.contains("locals: [0: Int(9)]"));
+    assert!(out.ends_with("stack: [Int(0), Int(362880)]\nlocals: []\n(leia) "));
+    assert!(printed.is_empty());
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use vm::{assembler::parse_assembly, disassembler::disassemble, instruction::Program};
+
+/// Disassembles and reassembles, then checks everything but the debug info survived
+fn assert_round_trips(program: &Program, name: &str) {
+    let text = disassemble(program);
+    let mut reassembled = parse_assembly(&text);
+    reassembled.debug = program.debug.clone();
+    assert_eq!(*program, reassembled, "{name}:\n{text}");
+}
+
+#[test]
+fn asm_programs_round_trip() {
+    for (file, program) in common::example_programs() {
+        assert_round_trips(&program, &file);
+    }
+}
+
+#[test]
+fn functions_and_natives_round_trip() {
+    let program = parse_assembly(
+        ".const 0 2\n.fn_main\n    PUSH_CONST 0\n    CALL double 1\n    CALL_NATIVE log\n    HALT\n.fn double 1 2\n    LOAD_LOCAL 0\n    LOAD_LOCAL 0\n    ADD\n    RET",
+    );
+    assert_eq!(1, program.functions.len());
+    assert_eq!(vec!["log"], program.natives);
+    assert_round_trips(&program, "inline");
+}
+
+#[test]
+fn generates_labels_without_debug_info() {
+    let mut program = parse_assembly(
+        ".const 0 1.0\n.const 1 \"a;b\"\n.fn_main\n    PUSH_CONST 0\n    JUMPZ done\n    CALL f\n.done\n    HALT\n.f\n    RET",
+    );
+    program.debug = Default::default();
+
+    assert_eq!(
+        ".const 0 1.0
+.const 1 \"a;b\"
+
+.fn_main ; entry point
+    PUSH_CONST 0 ; 1.0
+    JUMPZ L3
+    CALL L4
+
+.L3
+    HALT
+
+.L4
+    RET
+",
+        disassemble(&program)
+    );
+}
@@ -0,0 +1,52 @@
+use vm::{
+    assembler::parse_assembly,
+    instruction::{LeiaValue, Opcode},
+    vm::{RunOutcome, VM},
+};
+
+const LOOP: &str = "
+.const 0 1
+.main
+    PUSH_CONST 0
+.loop
+    JUMPNZ loop
+";
+
+#[test]
+fn stops_when_fuel_runs_out() {
+    let mut vm = VM::new(parse_assembly(LOOP));
+    assert_eq!(Ok(RunOutcome::OutOfFuel), vm.run_with_fuel(1000));
+    assert_eq!(Ok(RunOutcome::OutOfFuel), vm.run_with_fuel(1000));
+}
+
+#[test]
+fn resumes_with_state_intact() {
+    let asm = std::fs::read_to_string("../asm/factorial.s").unwrap();
+    let mut vm = VM::new(parse_assembly(&asm));
+    let output = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let sink = output.clone();
+    vm.set_output_handler(move |x| sink.borrow_mut().push(x.clone()));
+
+    let mut slices = 0;
+    while vm.run_with_fuel(5).unwrap() == RunOutcome::OutOfFuel {
+        slices += 1;
+    }
+    assert!(slices > 10);
+    assert_eq!(vec![LeiaValue::Int(362880)], *output.borrow());
+}
+
+#[test]
+fn per_opcode_costs() {
+    let mut vm = VM::new(parse_assembly(LOOP));
+    vm.set_fuel_cost(|opcode, _| match opcode {
+        Opcode::JumpIfNotZero(_) => 10,
+        _ => 1,
+    });
+    // PUSH_CONST, then two jumps, leaving 9 which can't pay for a third
+    assert_eq!(Ok(RunOutcome::OutOfFuel), vm.run_with_fuel(30));
+
+    let mut vm = VM::new(parse_assembly(".main\n    HALT"));
+    vm.set_fuel_cost(|_, _| 5);
+    assert_eq!(Ok(RunOutcome::OutOfFuel), vm.run_with_fuel(4));
+    assert_eq!(Ok(RunOutcome::Finished), vm.run_with_fuel(5));
+}
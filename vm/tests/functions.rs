@@ -0,0 +1,209 @@
+mod common;
+
+use vm::{
+    assembler::{AssembleErrorKind, parse_assembly, try_parse_assembly},
+    disassembler::disassemble,
+    instruction::{Function, Program},
+    verifier::{VerifyErrorKind, verify},
+    vm::{VM, VmErrorKind},
+};
+
+const PROGRAM: &str = "
+.const 0 10
+.const 1 3
+.fn_main
+    PUSH_CONST 0
+    PUSH_CONST 1
+    CALL sub 2
+    PRINT
+    HALT
+
+.fn sub 2 3
+    LOAD_LOCAL 0
+    LOAD_LOCAL 1
+    SUB
+    STORE_LOCAL 2
+    LOAD_LOCAL 2
+    RET
+";
+
+#[test]
+fn arguments_move_into_locals() {
+    let program = parse_assembly(PROGRAM);
+    assert_eq!(
+        vec![Function {
+            name: "sub".to_string(),
+            pc: 5,
+            arity: 2,
+            locals: 3
+        }],
+        program.functions
+    );
+    assert!(verify(&program).is_empty());
+
+    assert_eq!(vec!["7"], common::run_program(program));
+}
+
+#[test]
+fn arity_errors_name_the_function() {
+    let errors = try_parse_assembly(
+        ".main\n    CALL f 1\n    CALL g 0\n    HALT\n.fn f 2 2\n    RET\n.g\n    RET\n.fn h 2 1\n    RET",
+    )
+    .unwrap_err();
+    let kinds: Vec<_> = errors.iter().map(|e| (e.line, &e.kind)).collect();
+    assert_eq!(
+        vec![
+            (
+                2,
+                &AssembleErrorKind::ArityMismatch {
+                    function: "f".to_string(),
+                    expected: 2,
+                    found: 1
+                }
+            ),
+            (3, &AssembleErrorKind::NotAFunction("g".to_string())),
+            (
+                9,
+                &AssembleErrorKind::TooFewLocals {
+                    function: "h".to_string(),
+                    arity: 2,
+                    locals: 1
+                }
+            ),
+        ],
+        kinds
+    );
+
+    let mut vm = VM::new(parse_assembly(
+        ".const 0 1\n.main\n    PUSH_CONST 0\n    CALL f\n    HALT\n.fn f 2 2\n    RET",
+    ));
+    let err = vm.run().unwrap_err();
+    assert_eq!(
+        VmErrorKind::MissingArguments {
+            function: "f".to_string(),
+            expected: 2,
+            found: 1
+        },
+        err.kind
+    );
+    assert_eq!(
+        "function `f` takes 2 arguments but the stack only holds 1",
+        err.kind.to_string()
+    );
+}
+
+#[test]
+fn compiled_functions_keep_their_stack_to_themselves() {
+    // what the compiler emits for `fn f(n) { n; n * 2; }` called as `f(3);` and `print f(4);`
+    let program = parse_assembly(
+        ".const 0 3\n.const 1 4\n.const 2 2\n.fn_main\n    PUSH_CONST 0\n    CALL fn_f\n    POP\n    PUSH_CONST 1\n    CALL fn_f\n    PRINT\n    HALT\n\n.fn fn_f 1 1\n    LOAD_LOCAL 0\n    LOAD_LOCAL 0\n    PUSH_CONST 2\n    MUL\n    RET",
+    );
+    assert!(verify(&program).is_empty());
+    assert_eq!(vec!["8"], common::run_program(program));
+}
+
+#[test]
+fn extra_arguments_stay_with_the_caller() {
+    // `f` takes the last two of the three values, so the first is left on main's stack
+    let program = parse_assembly(
+        ".const 0 1\n.const 1 2\n.const 2 3\n.main\n    PUSH_CONST 0\n    PUSH_CONST 1\n    PUSH_CONST 2\n    CALL f 2\n    PRINT\n    HALT\n.fn f 2 2\n    LOAD_LOCAL 0\n    LOAD_LOCAL 1\n    ADD\n    RET",
+    );
+    let kinds: Vec<_> = verify(&program)
+        .into_iter()
+        .map(|e| (e.pc, e.kind))
+        .collect();
+    assert_eq!(vec![(5, VerifyErrorKind::StackNotEmptyAtHalt(1))], kinds);
+
+    let (output, result) = common::try_run_program(program);
+    assert_eq!(vec!["5"], output);
+    assert_eq!(VmErrorKind::StackNotEmpty(1), result.unwrap_err().kind);
+}
+
+#[test]
+fn declarations_round_trip() {
+    let program = parse_assembly(PROGRAM);
+    let text = disassemble(&program);
+    assert!(text.contains("\n.fn sub 2 3\n"));
+    assert_eq!(program.functions, parse_assembly(&text).functions);
+    assert_eq!(program, Program::from_bytes(&program.to_bytes()).unwrap());
+}
+
+#[test]
+fn locals_are_preallocated() {
+    // slot 2 is stored before slot 1, which is never written
+    let asm = "
+.const 0 5
+.fn_main
+    PUSH_CONST 0
+    CALL f 1
+    HALT
+.fn f 1 3
+    LOAD_LOCAL 0
+    STORE_LOCAL 2
+    LOAD_LOCAL 2
+    PRINT
+    LOAD_LOCAL 1
+    RET
+";
+    let mut vm = VM::new(parse_assembly(asm));
+    let output = common::capture_output(&mut vm);
+    let err = vm.run().unwrap_err();
+    assert_eq!(vec!["5"], *output.borrow());
+    assert_eq!(3, vm.locals().len());
+    assert_eq!(
+        "read of uninitialized local 1 in function `f`",
+        err.kind.to_string()
+    );
+}
+
+#[test]
+fn returns_drop_the_callee_stack() {
+    // `f` leaves an extra value behind, `g` returns nothing and `h` has no value to return
+    let asm = "
+.const 0 1
+.const 1 2
+.fn_main
+    PUSH_CONST 0
+    PUSH_CONST 1
+    CALL f 2
+    PRINT
+    CALL g 0
+    CALL h 0
+    PRINT
+    HALT
+.fn f 2 2
+    LOAD_LOCAL 0
+    LOAD_LOCAL 1
+    ADD
+    LOAD_LOCAL 0
+    RET
+.fn g 0 0
+    PUSH_CONST 0
+    RET_VOID
+.fn h 0 0
+    RET
+";
+    let program = parse_assembly(asm);
+    assert!(verify(&program).is_empty());
+
+    assert_eq!(vec!["1", "nil"], common::run_program(program));
+}
+
+#[test]
+fn callees_cannot_pop_their_caller_values() {
+    let asm = ".const 0 1\n.main\n    PUSH_CONST 0\n    CALL f 0\n    POP\n    POP\n    HALT\n.fn f 0 0\n    POP\n    RET";
+    let program = parse_assembly(asm);
+    assert_eq!(
+        vec![VerifyErrorKind::StackUnderflow {
+            depth: 0,
+            needed: 1
+        }],
+        verify(&program)
+            .into_iter()
+            .map(|x| x.kind)
+            .collect::<Vec<_>>()
+    );
+    let err = VM::new(program).run().unwrap_err();
+    assert_eq!(VmErrorKind::StackUnderflow, err.kind);
+    assert_eq!(5, err.pc);
+}
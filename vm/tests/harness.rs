@@ -33,7 +33,7 @@ fn compile_and_run(name: &str) -> Vec<String> {
     vm.set_output_handler(move |val| {
         output_clone.borrow_mut().push(format!("{}", val));
     });
-    vm.run();
+    vm.run().expect("Program failed at runtime");
     vm.clear_output_handler();
 
     Rc::try_unwrap(output).unwrap().into_inner()
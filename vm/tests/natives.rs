@@ -0,0 +1,82 @@
+mod common;
+
+use vm::{
+    assembler::parse_assembly,
+    disassembler::disassemble,
+    instruction::{LeiaValue, Program},
+    vm::{VM, VmErrorKind},
+};
+
+const PROGRAM: &str = r#"
+.const 0 3
+.const 1 4
+.const 2 "hypot"
+.main
+    PUSH_CONST 0
+    PUSH_CONST 1
+    CALL_NATIVE hypot
+    PRINT
+    PUSH_CONST 2
+    CALL_NATIVE log
+    POP
+    HALT
+"#;
+
+fn run(program: Program, fail: bool) -> (Result<(), VmErrorKind>, Vec<String>) {
+    let mut vm = VM::new(program);
+    let output = common::capture_output(&mut vm);
+    vm.register_native("hypot", 2, |args| match args {
+        [LeiaValue::Int(a), LeiaValue::Int(b)] => {
+            Ok(LeiaValue::Float(((a * a + b * b) as f64).sqrt()))
+        }
+        _ => Err("expected two ints".to_string()),
+    });
+    let sink = output.clone();
+    vm.register_native("log", 1, move |args| {
+        if fail {
+            return Err("log is full".to_string());
+        }
+        sink.borrow_mut().push(format!("log: {}", args[0]));
+        Ok(LeiaValue::Nil)
+    });
+    // never called, so registering it is harmless
+    vm.register_native("unused", 0, |_| Ok(LeiaValue::Nil));
+
+    let result = vm.run().map(|_| ()).map_err(|err| err.kind);
+    let output = output.borrow().clone();
+    (result, output)
+}
+
+#[test]
+fn calls_registered_natives() {
+    let (result, output) = run(parse_assembly(PROGRAM), false);
+    assert_eq!(Ok(()), result);
+    assert_eq!(vec!["5.0", "log: hypot"], output);
+}
+
+#[test]
+fn native_errors_are_runtime_errors() {
+    let (result, _) = run(parse_assembly(PROGRAM), true);
+    assert_eq!(
+        Err(VmErrorKind::Native {
+            name: "log".to_string(),
+            message: "log is full".to_string()
+        }),
+        result
+    );
+
+    let mut vm = VM::new(parse_assembly(".main\n    CALL_NATIVE missing\n    HALT"));
+    assert_eq!(
+        VmErrorKind::UnknownNative("missing".to_string()),
+        vm.run().unwrap_err().kind
+    );
+}
+
+#[test]
+fn natives_survive_bytecode_and_disassembly() {
+    let program = parse_assembly(PROGRAM);
+    assert_eq!(vec!["hypot", "log"], program.natives);
+    assert!(disassemble(&program).contains("    CALL_NATIVE log\n"));
+    assert_eq!(program, Program::from_bytes(&program.to_bytes()).unwrap());
+    assert_eq!(program.code, parse_assembly(&disassemble(&program)).code);
+}
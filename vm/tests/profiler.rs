@@ -0,0 +1,33 @@
+use vm::{assembler::parse_assembly, profiler::Profiler, vm::VM};
+
+#[test]
+fn profiles_factorial() {
+    let asm = std::fs::read_to_string("../asm/factorial.s").unwrap();
+    let mut vm = VM::new(parse_assembly(&asm));
+    vm.set_output_handler(|_| {});
+    let profiler = Profiler::attach(&mut vm);
+    vm.run().unwrap();
+    let profile = profiler.profile();
+
+    assert_eq!(116, profile.pcs.iter().sum::<u64>());
+    assert_eq!(Some(&9), profile.opcodes.get("CALL"));
+    assert_eq!(9, profile.pcs[5]);
+
+    let functions: Vec<_> = profile
+        .functions()
+        .into_iter()
+        .map(|x| (x.name, x.calls, x.self_instructions, x.total_instructions))
+        .collect();
+    assert_eq!(
+        vec![
+            ("factorial".to_string(), 9, 111, 111),
+            ("main".to_string(), 1, 5, 116)
+        ],
+        functions
+    );
+
+    let folded = profile.folded_stacks();
+    assert!(folded.starts_with("main 5\nmain;factorial 13\nmain;factorial;factorial 13\n"));
+    assert_eq!(10, folded.lines().count());
+    assert!(profile.report().contains("5 (factorial+0)"));
+}
@@ -0,0 +1,159 @@
+use std::{cell::RefCell, rc::Rc};
+
+use vm::{
+    assembler::parse_assembly,
+    instruction::{LeiaValue, Opcode},
+    vm::{ArithmeticMode, RunOutcome, StackLimit, VM, VmConfig, VmError, VmErrorKind},
+};
+
+fn run(asm: &str) -> Result<RunOutcome, VmError> {
+    let mut vm = VM::new(parse_assembly(asm));
+    vm.set_output_handler(|_| {});
+    vm.run()
+}
+
+#[test]
+fn division_by_zero() {
+    let err = run(".const 0 7\n.const 1 0\n.main\n PUSH_CONST 0\n PUSH_CONST 1\n DIV\n HALT")
+        .unwrap_err();
+    assert_eq!(VmErrorKind::DivisionByZero, err.kind);
+    assert_eq!(2, err.pc);
+    assert_eq!(Opcode::Divide, err.opcode);
+    assert_eq!(vec![LeiaValue::Int(7), LeiaValue::Int(0)], err.stack_top);
+}
+
+#[test]
+fn type_mismatch() {
+    let err = run(".const 0 1\n.const 1 \"a\"\n.main\n PUSH_CONST 0\n PUSH_CONST 1\n MUL\n HALT")
+        .unwrap_err();
+    assert_eq!(
+        VmErrorKind::TypeMismatch {
+            operation: "multiplication",
+            left: "Int",
+            right: "Str"
+        },
+        err.kind
+    );
+}
+
+#[test]
+fn stack_underflow_and_leftovers() {
+    let err = run(".main\n POP\n HALT").unwrap_err();
+    assert_eq!(VmErrorKind::StackUnderflow, err.kind);
+
+    let err = run(".const 0 1\n.main\n PUSH_CONST 0\n HALT").unwrap_err();
+    assert_eq!(VmErrorKind::StackNotEmpty(1), err.kind);
+
+    let err = run(".main\n LOAD_LOCAL 3\n HALT").unwrap_err();
+    assert_eq!(
+        VmErrorKind::UninitializedLocal {
+            index: 3,
+            function: "main".to_string()
+        },
+        err.kind
+    );
+
+    let err = run(".main\n RET").unwrap_err();
+    assert_eq!(VmErrorKind::CallStackUnderflow, err.kind);
+}
+
+#[test]
+fn locals_past_the_frame_limit() {
+    // programs built in code skip the assembler's checks on local slots
+    let mut program = parse_assembly(
+        ".const 0 1\n.main\n PUSH_CONST 0\n STORE_LOCAL 0\n LOAD_LOCAL 0\n HALT\n.fn f 0 0\n RET",
+    );
+    program.code[1] = Opcode::StoreLocal(5_000_000_000);
+    program.code[2] = Opcode::LoadLocal(usize::MAX);
+    program.functions[0].locals = 100_000_000_000;
+    let mut vm = VM::new(program);
+    let err = vm.run().unwrap_err();
+    assert_eq!(VmErrorKind::LocalOutOfRange(5_000_000_000), err.kind);
+}
+
+#[test]
+fn stack_overflow() {
+    let config = VmConfig {
+        max_stack: 4,
+        max_call_depth: 3,
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(
+        parse_assembly(".main\n    CALL f\n    HALT\n.f\n    CALL f"),
+        config,
+    );
+    let err = vm.run().unwrap_err();
+    assert_eq!(
+        VmErrorKind::StackOverflow {
+            limit: StackLimit::CallDepth(3),
+            frames: vec!["f", "f", "f", "main"]
+                .into_iter()
+                .map(String::from)
+                .collect()
+        },
+        err.kind
+    );
+    assert_eq!(
+        "stack overflow: calls can be at most 3 deep (in f, called from f, called from f, called from main)",
+        err.kind.to_string()
+    );
+
+    let mut vm = VM::with_config(
+        parse_assembly(".const 0 1\n.main\n    PUSH_CONST 0\n    JUMP main"),
+        config,
+    );
+    let err = vm.run().unwrap_err();
+    assert_eq!(
+        VmErrorKind::StackOverflow {
+            limit: StackLimit::Operand(4),
+            frames: vec!["main".to_string()]
+        },
+        err.kind
+    );
+    assert_eq!(4, err.stack_top.len());
+}
+
+#[test]
+fn overflow_depends_on_arithmetic_mode() {
+    let asm = ".const 0 9223372036854775807\n.const 1 1\n.main\n PUSH_CONST 0\n PUSH_CONST 1\n ADD\n PRINT\n HALT";
+    // with big integers these promote instead, see tests/bigint.rs
+    #[cfg(not(feature = "bigint"))]
+    assert_eq!(
+        VmErrorKind::IntegerOverflow("addition"),
+        run(asm).unwrap_err().kind
+    );
+
+    let config = VmConfig {
+        arithmetic: ArithmeticMode::Wrapping,
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(parse_assembly(asm), config);
+    let output = Rc::new(RefCell::new(vec![]));
+    let sink = output.clone();
+    vm.set_output_handler(move |x| sink.borrow_mut().push(x.clone()));
+    vm.run().unwrap();
+    assert_eq!(vec![LeiaValue::Int(i64::MIN)], *output.borrow());
+
+    let min = LeiaValue::Int(i64::MIN);
+    let minus_one = LeiaValue::Int(-1);
+    // promotes too with big integers
+    #[cfg(not(feature = "bigint"))]
+    assert_eq!(
+        Err(VmErrorKind::IntegerOverflow("division")),
+        min.div(&minus_one)
+    );
+    assert_eq!(Ok(min.clone()), min.wrapping_div(&minus_one));
+    assert_eq!(Ok(LeiaValue::Int(0)), min.modulo(&minus_one));
+    assert_eq!(
+        Err(VmErrorKind::DivisionByZero),
+        min.wrapping_modulo(&LeiaValue::Int(0))
+    );
+    assert_eq!(
+        Ok(LeiaValue::Int(-1)),
+        LeiaValue::Int(-7).modulo(&LeiaValue::Int(3))
+    );
+    assert_eq!(
+        Ok(LeiaValue::Int(1)),
+        LeiaValue::Int(7).modulo(&LeiaValue::Int(-3))
+    );
+}
@@ -0,0 +1,48 @@
+use vm::{
+    assembler::parse_assembly,
+    instruction::LeiaValue,
+    vm::{RunOutcome, StepOutcome, VM},
+};
+
+fn factorial() -> VM {
+    let asm = std::fs::read_to_string("../asm/factorial.s").unwrap();
+    let mut vm = VM::new(parse_assembly(&asm));
+    vm.set_output_handler(|_| {});
+    vm
+}
+
+#[test]
+fn step_exposes_state() {
+    let mut vm = factorial();
+    assert_eq!(0, vm.pc());
+
+    // PUSH_CONST 0, CALL factorial, STORE_LOCAL 0
+    for _ in 0..3 {
+        assert_eq!(Ok(StepOutcome::Running), vm.step());
+    }
+    assert_eq!(6, vm.pc());
+    assert!(vm.stack().is_empty());
+    assert_eq!(&[Some(LeiaValue::Int(9))], vm.locals());
+
+    let frames = vm.frames();
+    assert_eq!(2, frames.len());
+    assert_eq!(1, frames[1].return_address());
+    assert!(frames[0].locals().is_empty());
+}
+
+#[test]
+fn run_for_resumes() {
+    let mut vm = factorial();
+    let mut pauses = 0;
+    loop {
+        match vm.run_for(7).unwrap() {
+            RunOutcome::Paused => pauses += 1,
+            outcome => {
+                assert_eq!(RunOutcome::Finished, outcome);
+                break;
+            }
+        }
+    }
+    assert!(pauses > 5);
+    assert_eq!(Ok(StepOutcome::Finished), vm.step());
+}
@@ -0,0 +1,138 @@
+mod common;
+
+use common::run_output;
+
+use vm::{
+    assembler::parse_assembly,
+    instruction::{LeiaValue, Program},
+    verifier::verify,
+    vm::VmErrorKind,
+};
+
+fn str(x: &str) -> LeiaValue {
+    LeiaValue::Str(x.to_string())
+}
+
+#[test]
+fn add_joins_and_comparisons_order_strings() {
+    let asm = r#"
+.const 0 "Leia "
+.const 1 "the cat"
+.const 2 "apple"
+.const 3 "Zebra"
+.main
+    PUSH_CONST 0
+    PUSH_CONST 1
+    ADD
+    PRINT
+    PUSH_CONST 2
+    PUSH_CONST 3
+    LT
+    PRINT
+    PUSH_CONST 2
+    PUSH_CONST 2
+    EQ
+    PRINT
+    PUSH_CONST 1
+    PUSH_CONST 2
+    GTE
+    PRINT
+    HALT"#;
+    assert_eq!(
+        vec!["Leia the cat", "false", "true", "true"],
+        run_output(asm)
+    );
+    let (_, result) = common::try_run_program(parse_assembly(
+        ".const 0 \"a\"\n.const 1 1\n.main\n    PUSH_CONST 0\n    PUSH_CONST 1\n    ADD\n    HALT",
+    ));
+    assert_eq!(
+        VmErrorKind::TypeMismatch {
+            operation: "addition",
+            left: "Str",
+            right: "Int"
+        },
+        result.unwrap_err().kind
+    );
+}
+
+#[test]
+fn indexes_count_chars() {
+    let asm = r#"
+.const 0 "  héllo, wörld!  "
+.const 1 "wö"
+.const 2 1
+.const 3 5
+.main
+    PUSH_CONST 0
+    TRIM
+    STORE_LOCAL 0
+    LOAD_LOCAL 0
+    LEN
+    PRINT
+    LOAD_LOCAL 0
+    PUSH_CONST 1
+    INDEX_OF
+    PRINT
+    LOAD_LOCAL 0
+    PUSH_CONST 2
+    PUSH_CONST 3
+    SUBSTR
+    UPPER
+    PRINT
+    LOAD_LOCAL 0
+    LOWER
+    PRINT
+    HALT"#;
+    let program = parse_assembly(asm);
+    assert!(verify(&program).is_empty());
+    assert_eq!(program, Program::from_bytes(&program.to_bytes()).unwrap());
+    assert_eq!(vec!["13", "7", "ÉLLO", "héllo, wörld!"], run_output(asm));
+
+    assert_eq!(Ok(str("STRASSE")), str("straße").upper());
+    assert_eq!(Ok(LeiaValue::Int(-1)), str("cat").index_of(&str("dog")));
+    assert_eq!(
+        Err(VmErrorKind::SubstringOutOfBounds {
+            start: LeiaValue::Int(2),
+            end: LeiaValue::Int(4),
+            len: 3
+        }),
+        str("häh").substring(&LeiaValue::Int(2), &LeiaValue::Int(4))
+    );
+    assert_eq!(
+        "substring 2..4 is out of bounds for a string of 3 chars",
+        str("häh")
+            .substring(&LeiaValue::Int(2), &LeiaValue::Int(4))
+            .unwrap_err()
+            .to_string()
+    );
+}
+
+#[test]
+fn split_walks_through_parts() {
+    // prints each comma-separated part, stopping when SPLIT finds no more separators
+    let asm = r#"
+.const 0 "a,ü,,b"
+.const 1 ","
+.main
+    PUSH_CONST 0
+.loop
+    PUSH_CONST 1
+    SPLIT
+    STORE_LOCAL 0
+    PRINT
+    LOAD_LOCAL 0
+    JUMPZ done
+    JUMP loop
+.done
+    POP
+    HALT"#;
+    assert_eq!(vec!["a", "ü", "", "b"], run_output(asm));
+    assert_eq!(Err(VmErrorKind::EmptySeparator), str("abc").split(&str("")));
+    assert_eq!(
+        Err(VmErrorKind::InvalidOperand {
+            operation: "length",
+            value: "Int"
+        }),
+        LeiaValue::Int(3).length()
+    );
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use vm::{
+    assembler::parse_assembly,
+    instruction::{Opcode, Program},
+    optimizer,
+    verifier::verify,
+    vm::{StepOutcome, VM},
+};
+
+/// Sums 1..=n with an accumulator, recursing through a shared `RET`
+const SUM: &str = "
+.const 0 10000
+.const 1 0
+.const 2 1
+.fn_main
+    PUSH_CONST 0
+    PUSH_CONST 1
+    CALL sum 2
+    PRINT
+    HALT
+
+.fn sum 2 2
+    LOAD_LOCAL 0
+    JUMPZ done
+    POP
+    LOAD_LOCAL 0
+    PUSH_CONST 2
+    SUB
+    LOAD_LOCAL 1
+    LOAD_LOCAL 0
+    ADD
+    CALL sum 2
+    JUMP end
+.done
+    LOAD_LOCAL 1
+.end
+    RET
+";
+
+/// Runs the program, returning what it printed and the deepest the call stack got
+fn run(program: Program) -> (Vec<String>, usize) {
+    let mut vm = VM::new(program);
+    let output = common::capture_output(&mut vm);
+    let mut deepest = 0;
+    while vm.step().unwrap() == StepOutcome::Running {
+        deepest = deepest.max(vm.frames().len());
+    }
+    let output = output.borrow().clone();
+    (output, deepest)
+}
+
+#[test]
+fn rewritten_recursion_keeps_one_frame() {
+    let mut program = parse_assembly(SUM);
+    assert_eq!(1, optimizer::tail_calls(&mut program));
+    assert_eq!(Opcode::TailCall(5), program.code[14]);
+    assert!(verify(&program).is_empty());
+
+    let (output, deepest) = run(program);
+    assert_eq!(vec!["50005000"], output);
+    assert_eq!(2, deepest);
+}
+
+#[test]
+fn tail_calls_return_like_call_then_ret() {
+    // `g` returns nothing, so a `RET` after calling it returns whatever its caller
+    // left under the call, or nil if that's nothing
+    let asm = "
+.const 0 7
+.const 1 8
+.fn_main
+    CALL f 0
+    PRINT
+    CALL e 0
+    PRINT
+    PUSH_CONST 0
+    CALL h
+    PRINT
+    CALL m 0
+    PRINT
+    CALL n 0
+    PRINT
+    HALT
+.fn f 0 0
+    PUSH_CONST 0
+    CALL g 0
+    RET
+.fn e 0 0
+    CALL g 0
+    RET
+.fn g 0 0
+    RET_VOID
+.h
+    STORE_LOCAL 0
+    LOAD_LOCAL 0
+    CALL k 1
+    RET
+.fn k 1 1
+    LOAD_LOCAL 0
+    RET
+.fn m 0 0
+    PUSH_CONST 1
+    PUSH_CONST 0
+    CALL k 1
+    RET
+.fn n 0 0
+    PUSH_CONST 1
+    CALL f 0
+    RET
+";
+    let program = parse_assembly(asm);
+    let mut optimized = program.clone();
+    assert_eq!(5, optimizer::tail_calls(&mut optimized));
+    assert!(verify(&optimized).is_empty());
+
+    let (expected, _) = run(program);
+    assert_eq!(vec!["7", "nil", "7", "7", "7"], expected);
+    assert_eq!(expected, run(optimized).0);
+}
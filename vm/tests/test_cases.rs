@@ -1,26 +1,8 @@
-use std::{cell::RefCell, rc::Rc};
-
-use vm::{assembler::parse_assembly, vm::VM};
+mod common;
 
 pub fn run_asm_test(file: &str) -> Vec<String> {
     let asm = std::fs::read_to_string(file).expect("Failed to read .s file");
-    let program = parse_assembly(&asm);
-    let output = Rc::new(RefCell::new(Vec::new()));
-    let output_clone = Rc::clone(&output);
-
-    let mut vm = VM::new(program);
-
-    vm.set_output_handler(move |val| {
-        output_clone.borrow_mut().push(format!("{}", val));
-    });
-
-    vm.run();
-    vm.clear_output_handler();
-
-    // return collected output
-    Rc::try_unwrap(output)
-        .expect("Multiple references to output exist")
-        .into_inner()
+    common::run_output(&asm)
 }
 
 #[cfg(test)]
@@ -67,7 +49,7 @@ mod tests {
     #[test]
     fn test_comparisons() {
         let val = run_asm_test("../asm/compare.s");
-        let bools: Vec<bool> = val.iter().map(|x| x == "1").collect();
+        let bools: Vec<bool> = val.iter().map(|x| x == "true").collect();
         assert_eq!(vec![true, false, true, false, false, true, false], bools);
     }
 }
@@ -0,0 +1,93 @@
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+use vm::{assembler::parse_assembly, instruction::Opcode, trace, vm::VM};
+
+/// A writer the test can read back after the VM has taken ownership of it
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+fn factorial() -> VM {
+    let asm = std::fs::read_to_string("../asm/factorial.s").unwrap();
+    let mut vm = VM::new(parse_assembly(&asm));
+    vm.set_output_handler(|_| {});
+    vm
+}
+
+#[test]
+fn handler_sees_every_instruction() {
+    let events = Rc::new(RefCell::new(vec![]));
+    let sink = events.clone();
+    let mut vm = factorial();
+    vm.set_trace_handler(move |event| {
+        sink.borrow_mut().push((
+            event.pc,
+            event.opcode.clone(),
+            event.depth,
+            event.stack.len(),
+        ))
+    });
+    vm.run().unwrap();
+
+    let events = events.borrow();
+    assert_eq!(
+        (0, Opcode::Push(vm::instruction::ConstantIndex(0)), 0, 0),
+        events[0]
+    );
+    assert_eq!((5, Opcode::StoreLocal(0), 1, 1), events[2]);
+    assert_eq!(Some(&(4, Opcode::Halt, 0, 0)), events.last());
+    assert_eq!(9, events.iter().map(|x| x.2).max().unwrap());
+}
+
+#[test]
+fn built_in_sinks() {
+    let human = Shared::default();
+    let mut vm = factorial();
+    vm.set_trace_handler(trace::human_readable(human.clone()));
+    vm.run().unwrap();
+    assert!(human.text().starts_with(
+        "    0  PUSH_CONST 0  []\n    1  CALL 5  [Int(9)]\n    5    STORE_LOCAL 0  [Int(9)]\n"
+    ));
+
+    let json = Shared::default();
+    let mut vm = factorial();
+    vm.set_trace_handler(trace::json_lines(json.clone()));
+    vm.run().unwrap();
+    assert!(json.text().starts_with(
+        "{\"pc\":0,\"op\":\"PUSH_CONST\",\"operand\":0,\"depth\":0,\"stack\":[]}\n{\"pc\":1,\"op\":\"CALL\",\"operand\":5,\"depth\":0,\"stack\":[9]}\n"
+    ));
+
+    let chrome = Shared::default();
+    let mut vm = factorial();
+    vm.set_trace_handler(trace::chrome_trace(chrome.clone(), vm.program()));
+    vm.run().unwrap();
+    let text = chrome.text();
+    let phases: Vec<_> = text
+        .lines()
+        .map(|x| {
+            (
+                x.contains("\"ph\":\"B\""),
+                x.contains("\"name\":\"factorial\""),
+            )
+        })
+        .collect();
+    assert!(text.starts_with("[{\"name\":\"main\",\"ph\":\"B\""));
+    assert_eq!(2 * 10, phases.len());
+    assert_eq!(9, phases.iter().filter(|x| **x == (true, true)).count());
+    assert_eq!(9, phases.iter().filter(|x| **x == (false, true)).count());
+}
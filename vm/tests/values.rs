@@ -0,0 +1,182 @@
+mod common;
+
+use common::run_output;
+use vm::{assembler::parse_assembly, vm::VM};
+
+#[test]
+fn bool_and_nil_constants() {
+    let asm = "
+.const 0 True
+.const 1 false
+.const 2 nil
+.main
+    PUSH_CONST 0
+    PRINT
+    PUSH_CONST 1
+    PRINT
+    PUSH_CONST 2
+    PRINT
+    PUSH_CONST 2
+    PUSH_CONST 2
+    EQ
+    PRINT
+    PUSH_CONST 0
+    PUSH_CONST 1
+    NEQ
+    PRINT
+    HALT";
+    assert_eq!(
+        vec!["true", "false", "nil", "true", "true"],
+        run_output(asm)
+    );
+}
+
+#[test]
+fn values_of_different_types_are_unequal() {
+    let asm = r#"
+.const 0 nil
+.const 1 0
+.const 2 ""
+.const 3 false
+.main
+    PUSH_CONST 1
+    PUSH_CONST 0
+    EQ
+    PRINT
+    PUSH_CONST 2
+    PUSH_CONST 0
+    EQ
+    PRINT
+    PUSH_CONST 0
+    PUSH_CONST 3
+    NEQ
+    PRINT
+    PUSH_CONST 0
+    PUSH_CONST 0
+    EQ
+    PRINT
+    HALT"#;
+    assert_eq!(vec!["false", "false", "true", "true"], run_output(asm));
+}
+
+#[test]
+fn conditional_jumps_use_truthiness() {
+    // each value is printed only if it is truthy
+    let asm = "
+.const 0 0
+.const 1 0.0
+.const 2 nil
+.const 3 false
+.const 4 \"\"
+.const 5 1
+.const 6 true
+.main
+    PUSH_CONST 0
+    CALL print_truthy
+    PUSH_CONST 1
+    CALL print_truthy
+    PUSH_CONST 2
+    CALL print_truthy
+    PUSH_CONST 3
+    CALL print_truthy
+    PUSH_CONST 4
+    CALL print_truthy
+    PUSH_CONST 5
+    CALL print_truthy
+    PUSH_CONST 6
+    CALL print_truthy
+    HALT
+
+.print_truthy
+    JUMPZ falsy
+    PRINT
+    RET
+.falsy
+    POP
+    RET";
+    assert_eq!(vec!["", "1", "true"], run_output(asm));
+}
+
+#[test]
+fn ints_are_64_bit() {
+    let asm = "
+.const 0 3037000499
+.const 1 -9223372036854775808
+.const 2 1
+.main
+    PUSH_CONST 0
+    PUSH_CONST 0
+    MUL
+    PRINT
+    PUSH_CONST 1
+    PUSH_CONST 2
+    ADD
+    PRINT
+    HALT";
+    assert_eq!(
+        vec!["9223372030926249001", "-9223372036854775807"],
+        run_output(asm)
+    );
+}
+
+#[test]
+fn ints_promote_to_floats() {
+    let asm = "
+.const 0 1
+.const 1 2.5
+.const 2 3
+.main
+    PUSH_CONST 0
+    PUSH_CONST 1
+    ADD
+    PRINT
+    PUSH_CONST 2
+    PUSH_CONST 1
+    LT
+    PRINT
+    PUSH_CONST 1
+    PUSH_CONST 2
+    DIV
+    PRINT
+    PUSH_CONST 0
+    PUSH_CONST 0
+    TO_FLOAT
+    EQ
+    PRINT
+    HALT";
+    assert_eq!(
+        vec!["3.5", "false", "0.8333333333333334", "true"],
+        run_output(asm)
+    );
+}
+
+#[test]
+fn conversions() {
+    let asm = "
+.const 0 -2.7
+.const 1 \" 42 \"
+.const 2 true
+.const 3 1.5
+.main
+    PUSH_CONST 0
+    TO_INT
+    PRINT
+    PUSH_CONST 1
+    TO_INT
+    PRINT
+    PUSH_CONST 2
+    TO_FLOAT
+    PRINT
+    PUSH_CONST 3
+    TO_STR
+    PRINT
+    HALT";
+    assert_eq!(vec!["-2", "42", "1.0", "1.5"], run_output(asm));
+
+    let err = VM::new(parse_assembly(
+        ".const 0 \"4x\"\n.main\n PUSH_CONST 0\n TO_INT\n HALT",
+    ))
+    .run()
+    .unwrap_err();
+    assert_eq!("cannot convert Str(\"4x\") to Int", err.kind.to_string());
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use vm::{
+    assembler::parse_assembly,
+    verifier::{VerifyErrorKind, basic_blocks, verify},
+};
+
+#[test]
+fn example_programs_verify() {
+    for (file, program) in common::example_programs() {
+        let findings = verify(&program);
+        assert!(findings.is_empty(), "{file}: {findings:?}");
+    }
+}
+
+#[test]
+fn splits_basic_blocks() {
+    let program = parse_assembly(
+        ".const 0 1\n.main\n    PUSH_CONST 0\n.loop\n    JUMPZ end\n    JUMP loop\n.end\n    POP\n    HALT",
+    );
+    let blocks: Vec<_> = basic_blocks(&program)
+        .iter()
+        .map(|x| (x.start, x.end))
+        .collect();
+    assert_eq!(vec![(0, 1), (1, 2), (2, 3), (3, 5)], blocks);
+}
+
+#[test]
+fn reports_findings_with_labels() {
+    let asm = "
+.const 0 1
+.main
+    LOAD_LOCAL 0
+    JUMPZ skip
+    PUSH_CONST 0
+.skip
+    POP
+    POP
+    PUSH_CONST 0
+    HALT
+";
+    let findings = verify(&parse_assembly(asm));
+    let found: Vec<_> = findings
+        .iter()
+        .map(|x| (x.pc, x.label.as_deref(), &x.kind))
+        .collect();
+
+    assert_eq!(
+        vec![
+            (0, Some("main"), &VerifyErrorKind::UninitializedLocal(0)),
+            (
+                3,
+                Some("skip"),
+                &VerifyErrorKind::InconsistentStackDepth {
+                    expected: 1,
+                    found: 2
+                }
+            ),
+            (
+                4,
+                Some("skip"),
+                &VerifyErrorKind::StackUnderflow {
+                    depth: 0,
+                    needed: 1
+                }
+            ),
+            (6, Some("skip"), &VerifyErrorKind::StackNotEmptyAtHalt(1)),
+        ],
+        found
+    );
+    assert_eq!(
+        "pc 0 (main): local 0 is read before any path stores it",
+        findings[0].to_string()
+    );
+}
+
+#[test]
+fn checks_locals_past_native_calls() {
+    // the native's arity is unknown, so the POPs can't be checked but the LOAD_LOCAL can
+    let asm = "
+.const 0 \"x\"
+.main
+    PUSH_CONST 0
+    CALL_NATIVE log
+    LOAD_LOCAL 1
+    POP
+    POP
+    HALT
+";
+    let kinds: Vec<_> = verify(&parse_assembly(asm))
+        .into_iter()
+        .map(|x| (x.pc, x.kind))
+        .collect();
+    assert_eq!(vec![(2, VerifyErrorKind::UninitializedLocal(1))], kinds);
+}